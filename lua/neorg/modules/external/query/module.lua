@@ -40,7 +40,8 @@ local hop
 ---@field end number either the end of the content, or the end of the "setup"
 ---@field content_start number?
 ---@field type neorq.type
----@field sql string
+---@field sql string?
+---@field query string? source of a `#query` block
 ---@field format string
 ---@field tasks string
 
@@ -49,8 +50,45 @@ module.config.public = {
     --- Index the workspace on launch
     index_on_launch = true,
 
+    --- Keep every workspace in one database instead of one database per workspace. Queries still
+    --- only see the current workspace
+    shared_database = false,
+
     --- Update the db entry when a file is written
     update_on_change = true,
+
+    --- Watch the workspace for changes from outside of neovim too (other editors, git pulls, sync
+    --- tools). Replaces `update_on_change`
+    watch = false,
+
+    --- Number of threads used to parse files while indexing. Defaults to the number of available
+    --- cores
+    index_workers = nil,
+
+    --- Number of files written to the database per transaction while indexing. Larger batches make
+    --- full indexes faster
+    index_batch_size = 1,
+
+    --- Store the text of every paragraph so that notes can be searched with `search`. This makes
+    --- the database a lot larger. After turning this on, the next index parses every note again to
+    --- fill it in. Turning it off empties it
+    full_text = false,
+
+    --- Time zone for task dates that don't name one. Either an IANA name like `"America/New_York"`,
+    --- an abbreviation like `"EST"`, or `nil` for the system's time zone
+    timezone = nil,
+
+    --- `"strict"` to only accept dates written the way the norg spec describes, or `"lenient"` to
+    --- also accept things like `Jan 1 2025`, `2025-01-01`, `tomorrow`, and `next friday`
+    date_strictness = "strict",
+
+    --- Your own SQL views, `{ name = "SELECT ..." }`. They're made again every time the database is
+    --- opened, and can be used in queries like any other table
+    views = {},
+
+    --- Make `views` temp views, instead of storing them in the database file where other SQLite
+    --- tools can see them
+    temp_views = false,
 }
 
 module.setup = function()
@@ -115,31 +153,10 @@ module.load = function()
     hop = module.required["core.esupports.hop"]
     formatter = require("neorg_query.formatter")
 
-    local ws = dirman.get_current_workspace()
-    ---@type PathlibPath
-    local ws_path = ws[2]
-
-    local db_path = Path(vim.fn.stdpath("data")) / "neorg" / "query"
-    if not db_path:exists() then
-        db_path:mkdir(Path.permission("rwxr-xr-x"), true)
-    end
-
-    -- initialize the database connection, perform an initial index operation if requested
-    neorq_rs.init(
-        tostring(db_path / ("%s.sqlite"):format(ws[1])),
-        tostring(ws_path),
-        module.config.public.index_on_launch,
-        function(success)
-            if success then
-                vim.notify("[Neorg-Query] Done Indexing!")
-            else
-                vim.notify("[Neorg-Query] Error on initial workspace index", vim.log.levels.ERROR)
-            end
-        end
-    )
+    module.private.init_workspace(dirman.get_current_workspace())
 
     -- Setup autocommands
-    if module.config.public.update_on_change then
+    if module.config.public.update_on_change and not module.config.public.watch then
         module.private.augroup = vim.api.nvim_create_augroup("neorg-query", { clear = true })
         vim.api.nvim_create_autocmd("BufWrite", {
             pattern = "*.norg",
@@ -149,11 +166,11 @@ module.load = function()
                     return
                 end
 
-                neorq_rs.index(e.file, function(success)
-                    if success then
+                neorq_rs.index(e.file, function(removed, err)
+                    if removed then
                         log.trace("Indexed file:" .. e.file)
                     else
-                        log.error("Failed to index file " .. e.file)
+                        log.error(("Failed to index file %s (%s): %s"):format(e.file, err.kind, err.message))
                     end
                 end)
             end,
@@ -161,12 +178,89 @@ module.load = function()
     end
 end
 
+---Open the database for a workspace, closing the last one
+---@param ws table { name, path } as returned by dirman
+module.private.init_workspace = function(ws)
+    ---@type PathlibPath
+    local ws_path = ws[2]
+
+    local db_path = Path(vim.fn.stdpath("data")) / "neorg" / "query"
+    if not db_path:exists() then
+        db_path:mkdir(Path.permission("rwxr-xr-x"), true)
+    end
+
+    -- initialize the database connection, perform an initial index operation if requested
+    neorq_rs.init(
+        tostring(db_path / ("%s.sqlite"):format(module.config.public.shared_database and "neorg" or ws[1])),
+        tostring(ws_path),
+        module.config.public.index_on_launch,
+        {
+            workers = module.config.public.index_workers,
+            batch_size = module.config.public.index_batch_size,
+            full_text = module.config.public.full_text,
+            workspace_name = ws[1],
+            timezone = module.config.public.timezone,
+            date_strictness = module.config.public.date_strictness,
+            views = module.config.public.views,
+            temp_views = module.config.public.temp_views,
+        },
+        function(res, err)
+            if res then
+                vim.notify("[Neorg-Query] Done Indexing!")
+                for name, view_err in pairs(res.view_errors) do
+                    vim.notify(
+                        ("[Neorg-Query] Failed to create view `%s`: %s"):format(name, view_err),
+                        vim.log.levels.WARN
+                    )
+                end
+                if module.config.public.watch then
+                    local ok, watch_err = pcall(neorq_rs.watch, tostring(ws_path))
+                    if not ok then
+                        log.error("[Neorg-Query] Failed to watch workspace: " .. tostring(watch_err))
+                    end
+                end
+            else
+                vim.notify(
+                    ("[Neorg-Query] Failed to initialize (%s): %s"):format(err.kind, err.message),
+                    vim.log.levels.ERROR
+                )
+            end
+        end
+    )
+end
+
 ---@class external.query
 module.public = {
     --- provide a list of all the categories in the workspace to a callback function
-    ---@param cb fun(res: string[])
-    list_categories = function(cb)
-        neorq_rs.all_categories(cb)
+    ---@param cb fun(res: string[]?, err: neorq.error?)
+    ---@param workspace string? name of the workspace, defaults to the current one
+    list_categories = function(cb, workspace)
+        neorq_rs.all_categories(workspace or dirman.get_current_workspace()[1], cb)
+    end,
+
+    --- provide every link to a file, or to a heading in that file, to a callback function
+    ---@param path string absolute path, or `$/` workspace relative link target
+    ---@param heading string?
+    ---@param cb fun(res: BacklinkResponse[]?, err: neorq.error?)
+    backlinks = function(path, heading, cb)
+        neorq_rs.backlinks(path, heading, cb)
+    end,
+
+    --- full text search over the workspace, requires the `full_text` option
+    ---@param query string SQLite FTS5 query
+    ---@param limit integer? defaults to 20
+    ---@param cb fun(res: SearchResponse[]?, err: neorq.error?)
+    ---@param workspace string? name of the workspace, defaults to the current one
+    search = function(query, limit, cb, workspace)
+        neorq_rs.search(query, limit, workspace or dirman.get_current_workspace()[1], cb)
+    end,
+
+    --- provide everything that couldn't be parsed (like malformed task dates) to a callback
+    --- function, so it can be shown as diagnostics
+    ---@param path string? absolute path, or `$/` workspace relative link target. nil for every file
+    ---@param cb fun(res: DiagnosticResponse[]?, err: neorq.error?)
+    diagnostics = function(path, cb)
+        neorq_rs.diagnostics(path, cb)
     end,
 }
 
@@ -179,21 +273,30 @@ module.events.subscribed = {
     ["core.qol.todo_items"] = {
         ["todo-changed"] = true,
     },
+    ["core.dirman"] = {
+        ["workspace_changed"] = true,
+    },
 }
 
+---Switch databases along with the workspace
+---@param _ neorg.event
+module.private["workspace_changed"] = function(_)
+    module.private.init_workspace(dirman.get_current_workspace())
+end
+
 module.on_event = function(event)
     if module.private[event.split_type[2]] then
         module.private[event.split_type[2]](event)
     end
 end
 
---- find `#sql` tags, and the range around them
+--- find `#sql` and `#query` tags, and the range around them
 --- NOTE: we have to use regex to search b/c the TS parser for norg is so broken there's no
 --- reasonable way to use TS
 ---@param buf number
 ---@return neorq.tag[]
 module.private.find_sql_tags = function(buf)
-    local query_str = [[(strong_carryover name: ((tag_name) @name (#eq? @name "sql")))]]
+    local query_str = [[(strong_carryover name: ((tag_name) @name (#any-of? @name "sql" "query")))]]
     local lines = vim.api.nvim_buf_get_lines(buf, 0, -1, false)
 
     local neorq_group = {}
@@ -225,7 +328,9 @@ module.private.find_sql_tags = function(buf)
             lnr = lnr + 1
         end
 
-        if tags["sql"] == "" then
+        -- `#sql` is raw SQL, `#query` is our query language
+        local lang = tags["query"] and "query" or "sql"
+        if tags[lang] == "" then
             local verbatim = ts.get_first_node_on_line(buf, lnr - 1, "^verbatim$")
             if not verbatim then
                 return
@@ -233,16 +338,25 @@ module.private.find_sql_tags = function(buf)
             local verbatim_range = ts.get_node_range(verbatim)
             local content = ts.get_node_text(verbatim, buf)
 
-            tags["sql"] = content:match("`|(.*)|`") or ""
+            tags[lang] = content:match("`|(.*)|`") or ""
             lnr = verbatim_range.row_end + 2
         else
-            tags["sql"] = tags["sql"]:match("`|(.*)|`")
+            tags[lang] = tags[lang]:match("`|(.*)|`")
         end
 
-        if tags["sql"] == "" and not tags["tasks"] then
+        if tags[lang] == "" and not tags["tasks"] then
             return
         end
 
+        if lang == "query" and not tags.format then
+            -- task queries are drawn as tasks, doc queries as links
+            if tags["query"]:match("^%s*[Tt][Aa][Ss][Kk][Ss]") then
+                tags["tasks"] = tags["tasks"] or ""
+            else
+                tags["format"] = "`|- {:${path:$}:}[${title|path:t}]|`"
+            end
+        end
+
         if lnr <= #lines then
             if lines[lnr]:match("^%s*___$") then
                 tags["content_start"] = lnr
@@ -272,9 +386,24 @@ end
 
 ---Run the query, and return the formatted result via callback
 ---@param query string
----@param cb fun(res: table<string, any>)
+---@param cb fun(res: table<string, any>?, err: neorq.error?)
 module.private.sql_query = function(query, cb)
-    neorq_rs.user_query(query, {}, cb)
+    neorq_rs.user_query(query, {}, dirman.get_current_workspace()[1], cb)
+end
+
+---Run a `#tasks` query (SQL or query language), and return the matching tasks nested under their
+---parents via callback
+---@param source string
+---@param cb fun(res: table<string, any>?, err: neorq.error?)
+module.private.task_tree_query = function(source, cb)
+    neorq_rs.task_tree_query(source, {}, dirman.get_current_workspace()[1], cb)
+end
+
+---Run a query written in the query language, and return the result via callback
+---@param source string
+---@param cb fun(res: table<string, any>?, err: neorq.error?)
+module.private.lang_query = function(source, cb)
+    neorq_rs.query(source, dirman.get_current_workspace()[1], cb)
 end
 
 ---find the sql tag that the cursor is in, if it's in one
@@ -301,47 +430,34 @@ module.private["query.run"] = function(event)
 
     local line_indent = (" "):rep(indent.indentexpr(event.buffer, tag.start) or 0)
     local ws = dirman.get_current_workspace()[2]
-    module.private.sql_query(tag.sql, function(res)
+    local run = tag.query and module.private.lang_query or module.private.sql_query
+    if tag.tasks then
+        run = module.private.task_tree_query
+    end
+    run(tag.query or tag.sql, function(res, err)
+        if not res then
+            vim.notify(("[Neorg-Query] Query failed (%s): %s"):format(err.kind, err.message), vim.log.levels.ERROR)
+            return
+        end
         local lines = {}
         if tag.tasks then
-            -- create a tree
-            for ci, child in ipairs(res) do
-                if child.parent_id then
-                    for _pi, parent in ipairs(res) do
-                        if child.parent_id == parent.task_id then
-                            if parent.children then
-                                table.insert(parent.children, ci)
-                            else
-                                parent.children = { ci }
-                            end
-                        end
-                    end
+            -- the tree comes back already nested
+            local function draw(t, i)
+                -- link to the line when we have it, titles aren't unique
+                local target = t.start_line and "${start_line}" or "#${text}"
+                local formatted = ("${text} {:${path:$}:" .. target .. "}[]"):gsub("${(.-)}", function(name)
+                    return formatter.format_col(ws, name, t)
+                end)
+                local extensions = " " .. formatter.task_extensions(t)
+                table.insert(lines, line_indent .. ("-"):rep(i) .. extensions .. formatted)
+
+                for _, child in ipairs(t.children or {}) do
+                    draw(child, i + 1)
                 end
             end
 
-            -- render only the top level, and each top level will render it's children
             for _, task in ipairs(res) do
-                if task.parent_id then
-                    goto continue
-                end
-
-                local function draw(t, i)
-                    local formatted = ("${text} {:${path:$}:#${text}}[]"):gsub("${(.-)}", function(name)
-                        return formatter.format_col(ws, name, t)
-                    end)
-                    local extensions = " " .. formatter.task_extensions(t)
-                    table.insert(lines, line_indent .. ("-"):rep(i) .. extensions .. formatted)
-
-                    if t.children then
-                        for _, child_idx in ipairs(t.children) do
-                            draw(res[child_idx], i + 1)
-                        end
-                    end
-                end
-
                 draw(task, 1)
-
-                ::continue::
             end
         else
             for _, row in ipairs(res) do
@@ -370,11 +486,14 @@ end
 module.private["query.index"] = function(_)
     local ws = dirman.get_current_workspace()
 
-    neorq_rs.index(tostring(ws[2]), function(success)
-        if success then
-            vim.notify("[Neorg-Query] Done Indexing!")
+    neorq_rs.index(tostring(ws[2]), function(removed, err)
+        if removed then
+            vim.notify(("[Neorg-Query] Done Indexing! Removed %d missing file(s)"):format(removed))
         else
-            vim.notify("[Neorg-Query] Error while indexing workspace", vim.log.levels.ERROR)
+            vim.notify(
+                ("[Neorg-Query] Error while indexing workspace (%s): %s"):format(err.kind, err.message),
+                vim.log.levels.ERROR
+            )
         end
     end)
 end
@@ -442,7 +561,8 @@ module.private["todo-changed"] = function(event)
         "-i",
         "--column",
         "-o",
-        ([[^\s*\*+ \(.*\) %s]]):format(content),
+        -- heading and list item tasks
+        ([[^\s*[*~-]+ \(.*\) %s]]):format(content),
         tostring(path),
     }, { cwd = tostring(workspace_path)}):wait()
     if res.code == 0 then
@@ -9,21 +9,49 @@ local M = {}
 
 local PENDING = (coroutine.wrap(query.PENDING))()
 
+---@class neorq.error
+---@field kind "not_initialized"|"sql_error"|"io_error"|"parse_error"|"internal"
+---@field message string
+
+---Turn an error thrown by rust back into a neorq.error
+---@param err any
+---@return neorq.error
+local parse_error = function(err)
+    return query.error_info(err)
+end
+
+---Wrap a sync rust function so that its errors are thrown as a neorq.error
+---@param fn any
+---@return fun(args: ...): any
+local wrap_sync = function(fn)
+    return function(...)
+        local ok, res = pcall(fn, ...)
+        if not ok then
+            error(parse_error(res), 0)
+        end
+        return res
+    end
+end
+
 ---Wrap an async rust function in a coroutine that neovim will poll. Return a function that takes
----function args and a callback function
+---function args and a callback function. The callback gets the result, or nil and a neorq.error
 ---@param async_fn any
 ---@return fun(args: ...)
 local wrap = function(async_fn)
     return function(...)
+        -- `#args` isn't reliable when optional args are nil
+        local n = select("#", ...)
         local args = { ... }
-        local cb = args[#args]
-        args[#args] = nil
+        local cb = args[n]
+        args[n] = nil
 
-        local thread = coroutine.wrap(async_fn)
+        local thread = coroutine.create(async_fn)
         local exec
         exec = function()
-            local res = thread(unpack(args))
-            if res == PENDING then
+            local ok, res = coroutine.resume(thread, unpack(args, 1, n - 1))
+            if not ok then
+                cb(nil, parse_error(res))
+            elseif res == PENDING then
                 vim.defer_fn(exec, 10)
             else
                 cb(res)
@@ -33,30 +61,116 @@ local wrap = function(async_fn)
     end
 end
 
----@type fun(database_path: string, workspace_path: string, do_index: boolean, callback: fun(success: boolean))
+---@class neorq.init_opts
+---@field workers integer? number of threads used to parse files while indexing
+---@field batch_size integer? number of files written per transaction while indexing
+---@field full_text boolean? store the text of every paragraph for `search`
+---@field workspace_name string? name of the workspace, defaults to the name of its directory
+---@field timezone string? zone for dates without one, IANA name or abbreviation. Defaults to local
+---@field date_strictness "strict"|"lenient"? which date formats are accepted. Defaults to strict
+---@field views table<string, string>? views to create, name -> SELECT statement
+---@field temp_views boolean? make `views` temp views instead of storing them in the database
+
+---@class neorq.init_response
+---@field view_errors table<string, string> views that couldn't be created, name -> error
+
+---Open the database, closing the one that was open before (if any). Views that fail to validate
+---are skipped and listed in `view_errors`, they don't fail the init
+---@type fun(database_path: string, workspace_path: string, do_index: boolean, opts: neorq.init_opts?, callback: fun(res: neorq.init_response?, err: neorq.error?))
 M.init = wrap(query.init)
 
----@type fun(path: string, callback: fun(success: boolean))
+---Close the database connection, everything but `init` fails with `not_initialized` until `init`
+---is called again. Returns false if nothing was open. Not async
+---@type fun(): boolean
+M.close = wrap_sync(query.close)
+
+---Watch a directory for changes made outside of neovim (other editors, git, sync tools). Norg
+---files are indexed when they're created or changed and removed when they're deleted. Returns
+---false if the directory was already being watched. Not async, errors are thrown as a neorq.error
+---@type fun(path: string): boolean
+M.watch = wrap_sync(query.watch)
+
+---Stop watching `path`, or every watched directory when `path` is nil. Not async
+---@type fun(path: string?): boolean
+M.unwatch = wrap_sync(query.unwatch)
+
+---Index a file or directory. The callback receives the number of docs that were removed from the
+---index because they no longer exist on disk
+---@type fun(path: string, callback: fun(removed: integer?, err: neorq.error?))
 M.index = wrap(query.index)
 
 ---@class CategoryQueryResponse
 ---@field path string
 ---@field title string | nil
 ---@field description string | nil
----@field created string | nil
----@field updated string | nil
+---@field created integer | nil unix timestamp
+---@field updated integer | nil unix timestamp
 
----Query for all documents that have all the categories listed
----@type fun(categories: string[], callback: fun(res: CategoryQueryResponse[]))
+---Query for all documents that have all the categories listed, or any of them when `any` is true.
+---Only documents in the named workspace when `workspace` is given
+---@type fun(categories: string[], any: boolean?, workspace: string?, callback: fun(res: CategoryQueryResponse[]?, err: neorq.error?))
 M.category_query = wrap(query.category_query)
 
----Return a list of all the categories
----@type fun(callback: fun(res: string[]))
+---Return a list of all the categories, optionally only the ones used in the named workspace
+---@type fun(workspace: string?, callback: fun(res: string[]?, err: neorq.error?))
 M.all_categories = wrap(query.all_categories)
 
+---@class BacklinkResponse
+---@field path string path of the doc that contains the link
+---@field title string | nil
+---@field heading string | nil heading that the link is under
+---@field line integer | nil
+---@field kind string
+---@field target_heading string | nil heading that the link points to
+
+---Find every link to a file, or to a heading in that file. The path can be absolute, a `$/` or
+---`~/` link target, or relative to the workspace. `.norg` is optional unless it's absolute
+---@type fun(path: string, heading: string?, callback: fun(res: BacklinkResponse[]?, err: neorq.error?))
+M.backlinks = wrap(query.backlinks)
+
+---@class SearchResponse
+---@field path string
+---@field title string | nil
+---@field heading string | nil headings the match is under, joined with ` > `
+---@field line integer | nil
+---@field snippet string matched text, matching terms are surrounded by `*`
+---@field rank number bm25 rank, lower is better
+
+---Full text search over every document's body, best matches first. Uses the SQLite FTS5 query
+---syntax. Requires the `full_text` init option. Only documents in the named workspace when
+---`workspace` is given
+---@type fun(query: string, limit: integer?, workspace: string?, callback: fun(res: SearchResponse[]?, err: neorq.error?))
+M.search = wrap(query.search)
+
+---@class DiagnosticResponse
+---@field path string
+---@field line integer | nil
+---@field kind string `due`, `starts`, `timestamp`, `recurrence`, `created`, or `updated`
+---@field text string the text that couldn't be parsed
+---@field message string why it couldn't be parsed
+
+---Everything that couldn't be parsed in the file at `path` (same forms as `backlinks`), or in every
+---file when `path` is nil
+---@type fun(path: string?, callback: fun(res: DiagnosticResponse[]?, err: neorq.error?))
+M.diagnostics = wrap(query.diagnostics)
+
 ---Run a SQL query against a read only copy of the database. Result is a list of rows, keyed by
----column. Dates are returned in their string representation.
----@type fun(query: string, params: string[], callback: fun(res: table[]))
+---column. Columns declared as dates (even under another name, like `due AS d`) are unix
+---timestamps, computed values like `max(due)` are left as text. When `workspace` is given, the
+---query only sees that workspace's docs (and their tasks, categories, etc.)
+---@type fun(query: string, params: string[], workspace: string?, callback: fun(res: table[]?, err: neorq.error?))
 M.user_query = wrap(query.user_query)
 
+---Run a query written in the query language (see the README), like
+---`TASKS FROM #work WHERE due < today SORT due`. Results are the same shape as `user_query`'s.
+---Syntax errors are `parse_error`s with a message that starts with `line:col:`
+---@type fun(source: string, workspace: string?, callback: fun(res: table[]?, err: neorq.error?))
+M.query = wrap(query.query)
+
+---Tasks that match a query, nested under their parents as `children`. Ancestors of tasks that
+---matched are included so that every match shows up where it lives, `matched` tells them apart.
+---`query` is a `TASKS` query in the query language, or SQL that returns a `task_id` column
+---@type fun(query: string, params: string[]?, workspace: string?, callback: fun(res: table[]?, err: neorq.error?))
+M.task_tree_query = wrap(query.task_tree_query)
+
 return M
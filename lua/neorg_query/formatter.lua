@@ -5,7 +5,7 @@ local Path = require("pathlib")
 ---Format the variable according to any modifiers or fallback rules
 ---@param ws PathlibPath
 ---@param expr string
----@param row table<string, string>
+---@param row table<string, string|number>
 ---@return string
 M.format_col = function(ws, expr, row)
     for _, n in ipairs(vim.split(expr, "|")) do
@@ -29,12 +29,15 @@ end
 ---@param ws PathlibPath workspace path
 ---@param mod_string string group of modifiers like ":$:t"
 ---@param name string column name
----@param value string column value
+---@param value string|number column value
 ---@return string
 M.apply_mods = function(ws, mod_string, name, value)
     local mods = vim.split(mod_string, ":")
     for _, mod in ipairs(mods) do
-        if name == "path" then
+        -- dates come back as unix timestamps, `:d` shows them in local time
+        if mod == "d" and type(value) == "number" then
+            value = os.date("%Y-%m-%d %H:%M", value)
+        elseif name == "path" then
             if mod == "$" then
                 value = "$/" .. Path(value):relative_to(ws):with_suffix("")
             elseif mod == "t" then
@@ -58,7 +61,8 @@ M.task_extensions = function(task)
             ["Canceled"] = "_",
             ["Urgent"] = "!",
             ["Paused"] = "=",
-            -- NOTE: recurring is a special case that we're not supporting right now
+            -- the recurrence itself isn't kept in its original form, so it's left off
+            ["Recurring"] = "+",
         })[task.status])
     end
     if task.priority then
@@ -1,155 +1,439 @@
-use std::path::Path;
+use std::{collections::HashMap, future::Future, path::Path, sync::Arc};
 
 use anyhow::bail;
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use libsql::{params, Builder, Connection, OpenFlags, Rows};
-use log::{error, info, trace};
+use libsql::{params, Builder, Connection, Database, OpenFlags, Rows, Value};
+use log::{error, info, warn};
 use serde::Deserialize;
+use tokio::sync::Mutex;
 
 use crate::doc_parser::{ParsedDocument, Task};
+use crate::error::{ErrorKind, NeorqError};
+use crate::norg_date::{self, Recurrence, Zone};
+use crate::task_match::{self, TaskKey};
+
+/// What's stored for a doc, used to decide if it needs to be parsed again
+#[derive(Debug)]
+pub struct DocState {
+    /// modified time in ms when we last looked at the file
+    pub mtime: Option<i64>,
+    pub hash: Option<String>,
+    /// a migration asked for everything to be parsed again
+    pub stale: bool,
+}
+
+/// Query results, and the type that each column was declared with. Declared types follow a column
+/// through aliases and views (`due AS d` is still a `DATETIME`), but expressions don't have one
+pub struct TypedRows {
+    pub rows: Rows,
+    pub decl_types: Vec<Option<String>>,
+}
 
 #[derive(Clone)]
 pub struct DatabaseConnection {
     pub conn: Connection,
     pub read_conn: Connection,
+    /// Kept around to open connections that only see one workspace, see [Self::scoped]
+    read_db: Arc<Database>,
+    /// Held for the length of each write transaction. Clones share the same underlying connection,
+    /// so without this, two writers could end up inside of the same transaction
+    write_lock: Arc<Mutex<()>>,
+    /// The user's views from config, [Self::scoped] makes temp copies of them (and the built in
+    /// views) so they only see one workspace too
+    user_views: Arc<Vec<(String, String)>>,
+    /// Connections made by [Self::scoped], by workspace id. A new DatabaseConnection (`init`)
+    /// starts over, and so does changing the user's views
+    scoped_conns: Arc<Mutex<HashMap<i64, Connection>>>,
     // TODO: store prepared queries maybe? I'm not really sure how those work or how much
     // performance they gain
 }
 
 impl DatabaseConnection {
-    /// Create the database connection, and ensure that any tables we use exist
+    /// Create the database connection, and bring the schema up to date
     pub async fn new(db_file: &Path) -> anyhow::Result<DatabaseConnection> {
         let db = Builder::new_local(db_file).build().await?;
+        let conn = db.connect()?;
+        // readers don't block the writer (or the other way around) in WAL mode. It sticks to the
+        // file, so this only has to happen before the read only connection is opened
+        set_busy_timeout(&conn).await?;
+        conn.query("PRAGMA journal_mode = WAL", ()).await?;
+
         let read_db = Builder::new_local(db_file)
             .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
             .build()
             .await?;
-        let conn = db.connect()?;
         let read_conn = read_db.connect()?;
+        set_busy_timeout(&read_conn).await?;
 
-        conn.execute(
-            r#"CREATE TABLE IF NOT EXISTS docs
-            (id INTEGER PRIMARY KEY,
-            path VARCHAR(1024) UNIQUE NOT NULL,
-            title TEXT,
-            description TEXT,
-            authors TEXT,
-            created DATETIME,
-            updated DATETIME,
-            indexed DATETIME DEFAULT CURRENT_TIMESTAMP)"#,
-            (),
-        )
+        // another neovim can be opening the same database right now, so the whole schema is
+        // brought up to date in one transaction. Whoever goes second finds nothing left to do
+        let c = &conn;
+        transaction(c, move || async move {
+            if user_version(c).await? != MIGRATIONS.len() as i64 {
+                // views are made again below, and would otherwise get in the way of migrations
+                // that change the tables under them
+                drop_views(c).await?;
+                migrate(c).await?;
+            }
+            for (name, select) in BUILTIN_VIEWS {
+                replace_view(c, name, select).await?;
+            }
+            Ok(())
+        })
         .await?;
 
-        conn.execute(
-            r#"CREATE TRIGGER IF NOT EXISTS on_update_docs
-            AFTER UPDATE ON docs
-            FOR EACH ROW
-            BEGIN
-            UPDATE docs SET indexed = CURRENT_TIMESTAMP WHERE id = old.id;
-            END"#,
-            (),
-        )
-        .await?;
+        Ok(DatabaseConnection {
+            conn,
+            read_conn,
+            read_db: Arc::new(read_db),
+            write_lock: Arc::new(Mutex::new(())),
+            user_views: Arc::new(vec![]),
+            scoped_conns: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
 
-        // if we try to add a cat that already exists for a file, we just do nothing and continue
-        conn.execute(
-            r#"CREATE TABLE IF NOT EXISTS categories
-            (id INTEGER PRIMARY KEY,
-            file_id INTEGER,
-            name VARCHAR(255) NOT NULL,
-            FOREIGN KEY(file_id) REFERENCES docs(id),
-            UNIQUE (file_id, name) ON CONFLICT IGNORE)"#,
-            (),
-        )
-        .await?;
+    /// Create the user's views from config, replacing any persistent views from last time. Temp
+    /// views only live as long as this connection, persistent views are stored in the database so
+    /// other tools can use them too. A view that fails to validate is skipped (a persistent one
+    /// keeps its last good version), returns the error for each view that was skipped by name
+    pub async fn create_views(
+        &mut self,
+        views: &HashMap<String, String>,
+        temp: bool,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        let lock = self.write_lock.clone();
+        let _guard = lock.lock().await;
 
-        conn.execute(
-            r#"CREATE TABLE IF NOT EXISTS tasks
-            (task_id INTEGER PRIMARY KEY,
-            file_id INTEGER NOT NULL,
-            text TEXT NOT NULL,
-            status VARCHAR(32) NOT NULL,
-            due DATETIME,
-            starts DATETIME,
-            recurs DATETIME,
-            priority VARCHAR(32),
-            timestamp DATETIME,
-            parent_id INTEGER,
-            created DATETIME NOT NULL ON CONFLICT REPLACE DEFAULT CURRENT_TIMESTAMP,
-            updated DATETIME DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY(file_id) REFERENCES docs(id),
-            FOREIGN KEY(parent_id) REFERENCES tasks(task_id),
-            UNIQUE (file_id, parent_id, text) ON CONFLICT ABORT)"#,
-            (),
-        )
+        let mut errors = HashMap::new();
+        let mut created = vec![];
+        let mut create = |name: &String, select: &String, res: anyhow::Result<()>| match res {
+            Ok(()) => created.push((name.clone(), select.clone())),
+            Err(e) => {
+                warn!("Skipping view {name}: {e}");
+                errors.insert(name.clone(), e.to_string());
+            }
+        };
+
+        // persistent views are replaced in one transaction, so other instances (and other tools)
+        // never see them half done
+        let conn = &self.conn;
+        let persistent = transaction(conn, move || async move {
+            let mut rows = conn.query("SELECT name FROM user_views", ()).await?;
+            let mut old: Vec<String> = vec![];
+            while let Some(row) = rows.next().await? {
+                old.push(row.get(0)?);
+            }
+            drop(rows);
+            // views that have since been removed from config shouldn't stick around, and neither
+            // should any of them when they're temp views now
+            for name in old.iter().filter(|n| temp || !views.contains_key(*n)) {
+                conn.execute(&format!("DROP VIEW IF EXISTS \"{name}\""), ())
+                    .await?;
+                conn.execute("DELETE FROM user_views WHERE name = ?1", [name.as_str()])
+                    .await?;
+            }
+
+            let mut results = vec![];
+            if !temp {
+                for (name, select) in views.iter().sorted() {
+                    let res = create_view(conn, name, select, false).await;
+                    // a broken replacement leaves the last good version in place
+                    let kept = match res {
+                        Ok(()) => None,
+                        Err(_) => {
+                            let prefix = format!("CREATE VIEW \"{name}\" AS ");
+                            stored_view(conn, name)
+                                .await?
+                                .and_then(|sql| sql.strip_prefix(&prefix).map(String::from))
+                        }
+                    };
+                    results.push((name, select, res, kept));
+                }
+            }
+            Ok(results)
+        })
         .await?;
+        // kept views still need scoped copies, otherwise they'd see every workspace
+        let mut kept = vec![];
+        for (name, select, res, old) in persistent {
+            create(name, select, res);
+            kept.extend(old.map(|old| (name.clone(), old)));
+        }
+
+        if temp {
+            for (name, select) in views.iter().sorted() {
+                create(name, select, create_view(&self.read_conn, name, select, true).await);
+            }
+        }
 
-        Ok(DatabaseConnection { conn, read_conn })
+        created.extend(kept);
+        self.user_views = Arc::new(created);
+        // they'd still have the old views
+        self.scoped_conns = Arc::new(Mutex::new(HashMap::new()));
+        Ok(errors)
     }
 
-    /// Insert a doc or update it if it exists, returning the ID of the doc we just created.
+    /// Remember whether paragraphs are stored for full text search. When that changes, turning it
+    /// on marks every doc stale so unchanged files get their paragraphs on the next index, and
+    /// turning it off throws away the paragraphs we have
+    pub async fn set_full_text(&self, full_text: bool) -> anyhow::Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let conn = &self.conn;
+        transaction(conn, move || async move {
+            let mut rows = conn
+                .query("SELECT value FROM settings WHERE key = 'full_text'", ())
+                .await?;
+            // it was always off before we kept track
+            let old = match rows.next().await? {
+                Some(row) => row.get::<bool>(0)?,
+                None => false,
+            };
+            drop(rows);
+            if old == full_text {
+                return Ok(());
+            }
+
+            if full_text {
+                conn.execute("UPDATE docs SET indexed = NULL", ()).await?;
+            } else {
+                conn.execute("DELETE FROM body", ()).await?;
+            }
+            conn.execute(
+                "INSERT INTO settings (key, value) VALUES ('full_text', ?1)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                params![full_text as i64],
+            )
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Full text search over the paragraphs in `body`, best matches first. `query` uses the FTS5
+    /// query syntax. `body` isn't scoped to the workspace (see [Self::scoped]), joining it with
+    /// `docs` is what leaves out the other workspaces' paragraphs
+    pub async fn search(
+        &self,
+        query: &str,
+        limit: i64,
+        workspace: Option<&str>,
+    ) -> anyhow::Result<Rows> {
+        let q = "SELECT d.path, d.title, body.heading, body.line,
+                    snippet(body, 0, '*', '*', '...', 16), bm25(body) AS rank
+                 FROM body JOIN docs d ON d.id = body.file_id
+                 WHERE body MATCH ?1
+                 ORDER BY rank
+                 LIMIT ?2";
+        let params = vec![Value::from(query.to_string()), Value::from(limit)];
+        self.workspace_query(q, params, workspace).await
+    }
+
+    /// Insert a doc or update it if it exists, returning the ID of the doc we just created. All of
+    /// the doc's writes happen in one transaction, so the doc is either fully replaced or untouched
     pub async fn insert_or_update_doc(&self, doc: &mut ParsedDocument) -> anyhow::Result<i64> {
-        let mut rows = self.conn.query(
-            "INSERT INTO docs (path, title, description, authors, created, updated)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
-             ON CONFLICT(path) DO UPDATE SET title=excluded.title, description=excluded.description, authors=excluded.authors, updated=excluded.updated, created=excluded.created
-             RETURNING id",
-            doc.doc_params(),
-        ).await?;
+        let _guard = self.write_lock.lock().await;
 
-        let row = rows.next().await?;
-        if let Some(row) = row {
-            let id = *row
-                .get_value(0)?
-                .as_integer()
-                .ok_or(anyhow::anyhow!("ID isn't an int"))?;
+        let conn = &self.conn;
+        transaction(conn, move || write_doc(conn, doc)).await
+    }
 
-            self.conn
-                .execute("DELETE FROM categories WHERE file_id = ?1", [id])
-                .await?;
+    /// Insert or update a batch of docs in a single transaction. Each doc is written inside of its
+    /// own savepoint, so a doc that fails is rolled back without taking the rest of the batch with
+    /// it. Returns the result of writing each doc, in order
+    pub async fn insert_or_update_docs(
+        &self,
+        docs: &mut [ParsedDocument],
+    ) -> anyhow::Result<Vec<anyhow::Result<i64>>> {
+        let _guard = self.write_lock.lock().await;
 
-            // push new categories
-            if !doc.categories.is_empty() {
-                let values = (0..doc.categories.len())
-                    .map(|i| format!("(?{}, ?1)", i + 2))
-                    .collect_vec()
-                    .join(",");
-                let cat_query = format!("INSERT INTO categories (name, file_id) VALUES {values}");
-                let mut params = doc.categories.clone();
-                params.insert(0, id.to_string());
-
-                self.conn.execute(&cat_query, params).await?;
+        let conn = &self.conn;
+        transaction(conn, move || async move {
+            let mut results = Vec::with_capacity(docs.len());
+            for doc in docs.iter_mut() {
+                results.push(write_doc_savepoint(conn, doc).await?);
             }
+            Ok(results)
+        })
+        .await
+    }
 
-            add_tasks(&self.conn, doc, id).await?;
+    /// What we know about every doc we've stored under `root`, keyed by path. Loaded once per index
+    /// so that we don't need a query per file to decide what to parse
+    pub async fn doc_states(&self, root: &Path) -> anyhow::Result<HashMap<String, DocState>> {
+        let mut rows = self
+            .conn
+            .query(
+                &format!("SELECT path, mtime, hash, indexed IS NULL FROM docs WHERE {UNDER_ROOT}"),
+                params![under_root(root)],
+            )
+            .await?;
 
-            Ok(id)
-        } else {
-            bail!("Failed to fetch ID")
+        let mut states = HashMap::new();
+        while let Some(row) = rows.next().await? {
+            states.insert(
+                row.get::<String>(0)?,
+                DocState {
+                    mtime: row.get(1)?,
+                    hash: row.get(2)?,
+                    stale: row.get::<bool>(3)?,
+                },
+            );
+        }
+        Ok(states)
+    }
+
+    /// Record new modified times for docs whose contents didn't change
+    pub async fn touch_docs(&self, docs: &[(String, Option<i64>)]) -> anyhow::Result<()> {
+        if docs.is_empty() {
+            return Ok(());
         }
+        let _guard = self.write_lock.lock().await;
+        let conn = &self.conn;
+        transaction(conn, move || async move {
+            for (path, mtime) in docs {
+                conn.execute(
+                    "UPDATE docs SET mtime = ?2 WHERE path = ?1",
+                    params![path.clone(), *mtime],
+                )
+                .await?;
+            }
+            Ok(())
+        })
+        .await
     }
 
-    /// Get the `updated` date that we've stored for the file, parse it into a DateTime
-    pub async fn get_updated_date(&self, path: &str) -> anyhow::Result<DateTime<Utc>> {
+    /// Get the id and path of every doc that we've stored under `root`
+    pub async fn docs_under(&self, root: &Path) -> anyhow::Result<Vec<(i64, String)>> {
         let mut rows = self
             .conn
-            .query("SELECT indexed FROM docs WHERE path=?1", params![path])
+            .query(
+                &format!("SELECT id, path FROM docs WHERE {UNDER_ROOT}"),
+                params![under_root(root)],
+            )
             .await?;
 
-        match rows.next().await {
-            Ok(Some(row)) => match row.get_str(0) {
-                Ok(date) => {
-                    return Ok(NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")?.and_utc())
-                }
-                Err(e) => error!("Failed to get updated as string: {e:?}"),
-            },
-            Ok(None) => trace!("No rows (None) from query"),
-            Err(e) => error!("Error from query: {e:?}"),
+        let mut docs = vec![];
+        while let Some(row) = rows.next().await? {
+            docs.push((row.get(0)?, row.get(1)?));
         }
+        Ok(docs)
+    }
+
+    /// Remove docs along with the categories and tasks that belong to them
+    pub async fn remove_docs(&self, ids: &[i64]) -> anyhow::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.write_lock.lock().await;
 
-        bail!("No `updated` entry for this path");
+        let conn = &self.conn;
+        transaction(conn, move || async move {
+            for id in ids {
+                remove_doc(conn, *id).await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Move recurring tasks whose next occurrence has passed on to their following occurrence.
+    /// Their files haven't changed, so they won't be parsed again to do this for us. This isn't a
+    /// change to the task, so there's no event and `updated` stays put
+    pub async fn refresh_recurring(&self, zone: &Zone) -> anyhow::Result<()> {
+        let now_utc = Utc::now();
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT task_id, recurrence FROM tasks
+                 WHERE recurrence IS NOT NULL AND (recurs IS NULL OR recurs < ?1)",
+                params![norg_date::to_sql(now_utc)],
+            )
+            .await?;
+        let mut stale: Vec<(i64, String)> = vec![];
+        while let Some(row) = rows.next().await? {
+            stale.push((row.get(0)?, row.get(1)?));
+        }
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        let _guard = self.write_lock.lock().await;
+        let conn = &self.conn;
+        transaction(conn, move || async move {
+            for (id, rule) in stale {
+                let next = match Recurrence::from_rule(&rule) {
+                    Ok(r) => r.next_utc(zone).map(norg_date::to_sql),
+                    Err(e) => {
+                        warn!("Bad recurrence rule for task {id}: {e}");
+                        continue;
+                    }
+                };
+                conn.execute("UPDATE tasks SET recurs = ?1 WHERE task_id = ?2", params![next, id])
+                    .await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Add the workspace, or move it if there's already one with this name. Docs under `path`
+    /// that aren't in a workspace nested inside of it now belong to it. Docs written after that
+    /// get their workspace when they're written, so nothing's updated when the workspace is
+    /// already where it should be. Returns the workspace id
+    pub async fn register_workspace(&self, name: &str, path: &Path) -> anyhow::Result<i64> {
+        let path = path.to_string_lossy().trim_end_matches('/').to_string();
+        let name = name.to_string();
+        let _guard = self.write_lock.lock().await;
+
+        let conn = &self.conn;
+        transaction(conn, move || async move {
+            let mut rows = conn
+                .query("SELECT id, path FROM workspaces WHERE name = ?1", params![name.clone()])
+                .await?;
+            if let Some(row) = rows.next().await? {
+                if row.get::<String>(1)? == path {
+                    return Ok(row.get::<i64>(0)?);
+                }
+            }
+            drop(rows);
+
+            let mut rows = conn
+                .query(
+                    "INSERT INTO workspaces (name, path) VALUES (?1, ?2)
+                     ON CONFLICT(name) DO UPDATE SET path = excluded.path
+                     RETURNING id",
+                    params![name, path.clone()],
+                )
+                .await?;
+            let Some(row) = rows.next().await? else {
+                bail!("Failed to fetch workspace ID");
+            };
+            let id: i64 = row.get(0)?;
+            drop(rows);
+
+            // docs it left behind go to whatever workspace they're in now, if any
+            conn.execute(
+                "UPDATE docs SET workspace_id =
+                    (SELECT w.id FROM workspaces w
+                     WHERE substr(docs.path, 1, length(w.path) + 1) = w.path || '/'
+                     ORDER BY length(w.path) DESC LIMIT 1)
+                 WHERE workspace_id = ?1 AND substr(path, 1, length(?2) + 1) != ?2 || '/'",
+                params![id, path.clone()],
+            )
+            .await?;
+            conn.execute(
+                "UPDATE docs SET workspace_id = ?1
+                 WHERE substr(path, 1, length(?2) + 1) = ?2 || '/'
+                 AND (workspace_id IS NULL OR workspace_id NOT IN
+                    (SELECT w.id FROM workspaces w WHERE length(w.path) > length(?2)
+                     AND substr(docs.path, 1, length(w.path) + 1) = w.path || '/'))",
+                params![id, path],
+            )
+            .await?;
+            Ok(id)
+        })
+        .await
     }
 
     /// Execute a query in read only mode, return the result
@@ -158,25 +442,714 @@ impl DatabaseConnection {
         query: &str,
         params: impl params::IntoParams + std::fmt::Debug,
     ) -> anyhow::Result<Rows> {
+        self.workspace_query(query, params, None).await
+    }
+
+    /// [Self::user_query], but when `workspace` is given the query only sees that workspace's docs
+    pub async fn workspace_query(
+        &self,
+        query: &str,
+        params: impl params::IntoParams + std::fmt::Debug,
+        workspace: Option<&str>,
+    ) -> anyhow::Result<Rows> {
+        Ok(self.typed_query(query, params, workspace).await?.rows)
+    }
+
+    /// [Self::workspace_query], along with the declared type of each column
+    pub async fn typed_query(
+        &self,
+        query: &str,
+        params: impl params::IntoParams + std::fmt::Debug,
+        workspace: Option<&str>,
+    ) -> anyhow::Result<TypedRows> {
         info!("Running Query: {query}");
         info!("With Params: {params:?}");
-        Ok(self.read_conn.query(query, params).await?)
+        let conn = self.scoped(workspace).await?;
+        let mut stmt = conn.prepare(query).await?;
+        let decl_types = stmt
+            .columns()
+            .iter()
+            .map(|c| c.decl_type().map(str::to_string))
+            .collect();
+        let rows = stmt.query(params).await?;
+        Ok(TypedRows { rows, decl_types })
+    }
+
+    /// A read only connection where the tables that hold docs (and everything that belongs to a
+    /// doc) only have rows from `workspace`. This is done with temp views that shadow the real
+    /// tables, so each workspace gets a connection of its own (otherwise the views would stick
+    /// around for everyone else), which is kept for the next query. `body` is a virtual table and
+    /// can't be shadowed, it has to be joined with `docs` to filter it
+    async fn scoped(&self, workspace: Option<&str>) -> anyhow::Result<Connection> {
+        let Some(name) = workspace else {
+            return Ok(self.read_conn.clone());
+        };
+
+        let mut rows = self
+            .read_conn
+            .query("SELECT id FROM workspaces WHERE name = ?1", [name])
+            .await?;
+        let Some(row) = rows.next().await? else {
+            return Err(NeorqError::new(
+                ErrorKind::ParseError,
+                format!("no workspace named {name:?}"),
+            )
+            .into());
+        };
+        let id: i64 = row.get(0)?;
+
+        let mut conns = self.scoped_conns.lock().await;
+        if let Some(conn) = conns.get(&id) {
+            return Ok(conn.clone());
+        }
+        let conn = self.read_db.connect()?;
+        set_busy_timeout(&conn).await?;
+        // views can't take parameters, `id` is an int so this is fine
+        conn.execute(
+            &format!("CREATE TEMP VIEW docs AS SELECT * FROM main.docs WHERE workspace_id = {id}"),
+            (),
+        )
+        .await?;
+        for table in ["categories", "tasks", "links", "task_events", "diagnostics"] {
+            conn.execute(
+                &format!(
+                    "CREATE TEMP VIEW {table} AS SELECT * FROM main.{table}
+                     WHERE file_id IN (SELECT id FROM temp.docs)"
+                ),
+                (),
+            )
+            .await?;
+        }
+        // temp views are looked up before main ones, so these see the filtered tables. The user's
+        // views were checked when they were first created
+        let builtin = BUILTIN_VIEWS
+            .iter()
+            .map(|&(n, s)| (n.to_string(), s.to_string()));
+        for (name, select) in builtin.chain(self.user_views.iter().cloned()) {
+            if let Err(e) = conn
+                .execute(&format!("CREATE TEMP VIEW \"{name}\" AS {select}"), ())
+                .await
+            {
+                warn!("Failed to create view {name} for workspace {workspace:?}: {e}");
+            }
+        }
+        conns.insert(id, conn.clone());
+        Ok(conn)
     }
 }
 
+/// Docs at `?1` or under it, with `?1` from [under_root]. Everything that starts with `?1/` is
+/// written as a range so the index on path is used, `0` is the character after `/`
+const UNDER_ROOT: &str = "(path = ?1 OR (path >= ?1 || '/' AND path < ?1 || '0'))";
+
+fn under_root(root: &Path) -> String {
+    root.to_string_lossy().trim_end_matches('/').to_string()
+}
+
+/// Views that every database has. They're made again on startup when their definition changed, so
+/// changing one doesn't need a migration
+const BUILTIN_VIEWS: &[(&str, &str)] = &[
+    (
+        "open_tasks",
+        "SELECT t.*, d.path, d.title FROM tasks t JOIN docs d ON d.id = t.file_id
+         WHERE t.status NOT IN ('Done', 'Canceled')",
+    ),
+    (
+        "overdue_tasks",
+        "SELECT * FROM open_tasks WHERE due < datetime('now')",
+    ),
+    (
+        "docs_with_categories",
+        "SELECT d.*, group_concat(c.name, ', ') AS categories FROM docs d
+         LEFT JOIN categories c ON c.file_id = d.id GROUP BY d.id",
+    ),
+    (
+        "recent_docs",
+        "SELECT * FROM docs WHERE indexed >= datetime('now', '-7 days') ORDER BY indexed DESC",
+    ),
+];
+
+/// Create the built in view `name`, or replace it if it was made from a different definition. A
+/// view that's already right is left alone, queries that are using it don't notice a thing
+async fn replace_view(conn: &Connection, name: &str, select: &str) -> anyhow::Result<()> {
+    let sql = format!("CREATE VIEW \"{name}\" AS {select}");
+    let mut rows = conn
+        .query("SELECT sql FROM sqlite_master WHERE type = 'view' AND name = ?1", [name])
+        .await?;
+    let old: Option<String> = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => None,
+    };
+    drop(rows);
+    if old.as_deref() == Some(sql.as_str()) {
+        return Ok(());
+    }
+    if old.is_some() {
+        conn.execute(&format!("DROP VIEW \"{name}\""), ()).await?;
+    }
+    conn.execute(&sql, ()).await?;
+    Ok(())
+}
+
+/// Drop the built in views, and any persistent views that the user asked for last time. Only
+/// needed before migrations, the views are made again afterwards
+async fn drop_views(conn: &Connection) -> anyhow::Result<()> {
+    let mut names: Vec<String> = BUILTIN_VIEWS.iter().map(|(n, _)| n.to_string()).collect();
+    // doesn't exist before migration 13
+    if let Ok(mut rows) = conn.query("SELECT name FROM user_views", ()).await {
+        while let Some(row) = rows.next().await? {
+            names.push(row.get(0)?);
+        }
+    }
+    for name in names {
+        conn.execute(&format!("DROP VIEW IF EXISTS \"{name}\""), ())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Validate and create one of the user's views. Persistent views are recorded in `user_views` so
+/// we know to drop them later. One that's there from last time is only replaced when its
+/// definition changed, and the old one stays if the new one doesn't validate
+async fn create_view(
+    conn: &Connection,
+    name: &str,
+    select: &str,
+    temp: bool,
+) -> anyhow::Result<()> {
+    let mut chars = name.chars();
+    if !chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        bail!("{name:?} isn't a valid view name");
+    }
+    let select = select.trim().trim_end_matches(';').trim_end();
+    let first = select
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_uppercase();
+    if !(first == "SELECT" || first == "WITH") || select.contains(';') {
+        bail!("views must be a single SELECT statement");
+    }
+
+    let kind = if temp { "TEMP VIEW" } else { "VIEW" };
+    let sql = format!("CREATE {kind} \"{name}\" AS {select}");
+    // missing tables and columns aren't caught until the view is used
+    let validate = format!("SELECT * FROM \"{name}\" LIMIT 0");
+
+    let ours = stored_view(conn, name).await?;
+    if ours.is_some() && ours.as_deref() == Some(sql.as_str()) {
+        // the tables under it could've changed though
+        conn.query(&validate, ()).await?;
+        return Ok(());
+    }
+    if ours.is_none() {
+        let mut rows = conn
+            .query(
+                "SELECT 1 FROM sqlite_master WHERE name = ?1 UNION ALL
+                 SELECT 1 FROM sqlite_temp_master WHERE name = ?1",
+                [name],
+            )
+            .await?;
+        let exists = rows.next().await?.is_some();
+        drop(rows);
+        if exists {
+            bail!("{name} already exists");
+        }
+    }
+
+    conn.execute("SAVEPOINT user_view", ()).await?;
+    let res = async {
+        if ours.is_some() {
+            conn.execute(&format!("DROP VIEW \"{name}\""), ()).await?;
+        }
+        conn.execute(&sql, ()).await?;
+        conn.query(&validate, ()).await?;
+        if !temp {
+            // `drop_views` leaves the names behind
+            conn.execute("INSERT OR IGNORE INTO user_views (name) VALUES (?1)", [name])
+                .await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if res.is_err() {
+        conn.execute("ROLLBACK TO user_view", ()).await?;
+    }
+    conn.execute("RELEASE user_view", ()).await?;
+    res
+}
+
+/// The statement that one of the user's persistent views was made with. Sqlite keeps it as it was
+/// written, that's how we tell if a view changed
+async fn stored_view(conn: &Connection, name: &str) -> anyhow::Result<Option<String>> {
+    let mut rows = conn
+        .query(
+            "SELECT m.sql FROM sqlite_master m JOIN user_views u ON u.name = m.name
+             WHERE m.type = 'view' AND m.name = ?1",
+            [name],
+        )
+        .await?;
+    Ok(match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => None,
+    })
+}
+
+/// How long a connection waits for another one to let go of the database before it gives up with
+/// SQLITE_BUSY
+const BUSY_TIMEOUT_MS: u32 = 5000;
+
+async fn set_busy_timeout(conn: &Connection) -> anyhow::Result<()> {
+    // this pragma returns the new timeout, so it can't go through `execute`
+    conn.query(&format!("PRAGMA busy_timeout = {BUSY_TIMEOUT_MS}"), ())
+        .await?;
+    Ok(())
+}
+
+/// Run `f` inside of a `BEGIN IMMEDIATE` transaction. If anything fails, including the COMMIT
+/// itself (SQLITE_BUSY can do that), the transaction is rolled back, so `conn` is never left
+/// stuck inside of it. Callers hold the write lock
+async fn transaction<T, F, Fut>(conn: &Connection, f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    conn.execute("BEGIN IMMEDIATE", ()).await?;
+    let res = match f().await {
+        Ok(v) => conn.execute("COMMIT", ()).await.map(|_| v).map_err(Into::into),
+        Err(e) => Err(e),
+    };
+    if res.is_err() {
+        // errors like SQLITE_FULL roll back on their own, then there's nothing left to roll back
+        if let Err(e) = conn.execute("ROLLBACK", ()).await {
+            warn!("Failed to roll back: {e}");
+        }
+    }
+    res
+}
+
+/// Every change that we've made to the schema, in order. Each migration is a list of statements,
+/// afterwards `PRAGMA user_version` is set to the number of migrations that have been applied. The
+/// pending migrations all run in one transaction. Never edit a migration that's been released, add
+/// a new one
+const MIGRATIONS: &[&[&str]] = &[
+    // 1: initial schema. These use `IF NOT EXISTS` b/c databases from before we tracked versions
+    // already have them
+    &[
+        r#"CREATE TABLE IF NOT EXISTS docs
+        (id INTEGER PRIMARY KEY,
+        path VARCHAR(1024) UNIQUE NOT NULL,
+        title TEXT,
+        description TEXT,
+        authors TEXT,
+        created DATETIME,
+        updated DATETIME,
+        indexed DATETIME DEFAULT CURRENT_TIMESTAMP)"#,
+        r#"CREATE TRIGGER IF NOT EXISTS on_update_docs
+        AFTER UPDATE ON docs
+        FOR EACH ROW
+        BEGIN
+        UPDATE docs SET indexed = CURRENT_TIMESTAMP WHERE id = old.id;
+        END"#,
+        // if we try to add a cat that already exists for a file, we just do nothing and continue
+        r#"CREATE TABLE IF NOT EXISTS categories
+        (id INTEGER PRIMARY KEY,
+        file_id INTEGER,
+        name VARCHAR(255) NOT NULL,
+        FOREIGN KEY(file_id) REFERENCES docs(id),
+        UNIQUE (file_id, name) ON CONFLICT IGNORE)"#,
+        r#"CREATE TABLE IF NOT EXISTS tasks
+        (task_id INTEGER PRIMARY KEY,
+        file_id INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        status VARCHAR(32) NOT NULL,
+        due DATETIME,
+        starts DATETIME,
+        recurs DATETIME,
+        priority VARCHAR(32),
+        timestamp DATETIME,
+        parent_id INTEGER,
+        created DATETIME NOT NULL ON CONFLICT REPLACE DEFAULT CURRENT_TIMESTAMP,
+        updated DATETIME DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(file_id) REFERENCES docs(id),
+        FOREIGN KEY(parent_id) REFERENCES tasks(task_id),
+        UNIQUE (file_id, parent_id, text) ON CONFLICT ABORT)"#,
+    ],
+    // 2: links between documents
+    &[
+        r#"CREATE TABLE links
+        (id INTEGER PRIMARY KEY,
+        file_id INTEGER NOT NULL,
+        line INTEGER,
+        kind VARCHAR(32) NOT NULL,
+        target_path TEXT,
+        target_heading TEXT,
+        FOREIGN KEY(file_id) REFERENCES docs(id))"#,
+        "CREATE INDEX links_target ON links(target_path)",
+    ],
+    // 3: resolved link targets for backlinks
+    &[
+        "ALTER TABLE links ADD COLUMN resolved_path TEXT",
+        "ALTER TABLE links ADD COLUMN source_heading TEXT",
+        "CREATE INDEX links_resolved ON links(resolved_path)",
+        // let `indexed` be set explicitly, so that we can ask for docs to be parsed again
+        "DROP TRIGGER on_update_docs",
+        r#"CREATE TRIGGER on_update_docs
+        AFTER UPDATE ON docs
+        FOR EACH ROW WHEN new.indexed IS old.indexed
+        BEGIN
+        UPDATE docs SET indexed = CURRENT_TIMESTAMP WHERE id = old.id;
+        END"#,
+        // existing links don't have the new columns, parse everything again
+        "UPDATE docs SET indexed = NULL",
+    ],
+    // 4: full text search over paragraphs, only populated when `full_text` is enabled
+    &[
+        r#"CREATE VIRTUAL TABLE body USING fts5
+        (text,
+        heading UNINDEXED,
+        line UNINDEXED,
+        file_id UNINDEXED)"#,
+    ],
+    // 5: list item tasks
+    &[
+        "ALTER TABLE tasks ADD COLUMN kind VARCHAR(16) NOT NULL DEFAULT 'heading'",
+        // list tasks were never indexed, parse everything again
+        "UPDATE docs SET indexed = NULL",
+    ],
+    // 6: where tasks are in their file
+    &[
+        "ALTER TABLE tasks ADD COLUMN level INTEGER NOT NULL DEFAULT 0",
+        "ALTER TABLE tasks ADD COLUMN start_line INTEGER",
+        "ALTER TABLE tasks ADD COLUMN end_line INTEGER",
+        "ALTER TABLE tasks ADD COLUMN start_col INTEGER",
+        "ALTER TABLE docs ADD COLUMN line_count INTEGER",
+        "UPDATE docs SET indexed = NULL",
+    ],
+    // 7: drop the unique constraint on tasks, sibling tasks are allowed to share a title. SQLite
+    // can't drop constraints, so the table's rebuilt
+    &[
+        r#"CREATE TABLE tasks_new
+        (task_id INTEGER PRIMARY KEY,
+        file_id INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        status VARCHAR(32) NOT NULL,
+        due DATETIME,
+        starts DATETIME,
+        recurs DATETIME,
+        priority VARCHAR(32),
+        timestamp DATETIME,
+        parent_id INTEGER,
+        created DATETIME NOT NULL ON CONFLICT REPLACE DEFAULT CURRENT_TIMESTAMP,
+        updated DATETIME DEFAULT CURRENT_TIMESTAMP,
+        kind VARCHAR(16) NOT NULL DEFAULT 'heading',
+        level INTEGER NOT NULL DEFAULT 0,
+        start_line INTEGER,
+        end_line INTEGER,
+        start_col INTEGER,
+        FOREIGN KEY(file_id) REFERENCES docs(id),
+        FOREIGN KEY(parent_id) REFERENCES tasks(task_id))"#,
+        "INSERT INTO tasks_new SELECT task_id, file_id, text, status, due, starts, recurs, priority,
+            timestamp, parent_id, created, updated, kind, level, start_line, end_line, start_col
+         FROM tasks",
+        "DROP TABLE tasks",
+        "ALTER TABLE tasks_new RENAME TO tasks",
+        "CREATE INDEX tasks_file ON tasks(file_id)",
+    ],
+    // 8: history of task changes
+    &[
+        r#"CREATE TABLE task_events
+        (id INTEGER PRIMARY KEY,
+        task_id INTEGER NOT NULL,
+        file_id INTEGER NOT NULL,
+        field VARCHAR(32) NOT NULL,
+        old_value,
+        new_value,
+        at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(task_id) REFERENCES tasks(task_id),
+        FOREIGN KEY(file_id) REFERENCES docs(id))"#,
+        "CREATE INDEX task_events_task ON task_events(task_id)",
+    ],
+    // 9: recurring tasks
+    &[
+        "ALTER TABLE tasks ADD COLUMN recurrence TEXT",
+        "UPDATE docs SET indexed = NULL",
+    ],
+    // 10: things we couldn't parse
+    &[
+        r#"CREATE TABLE diagnostics
+        (id INTEGER PRIMARY KEY,
+        file_id INTEGER NOT NULL,
+        line INTEGER,
+        kind VARCHAR(32) NOT NULL,
+        text TEXT NOT NULL,
+        message TEXT NOT NULL,
+        FOREIGN KEY(file_id) REFERENCES docs(id))"#,
+        "CREATE INDEX diagnostics_file ON diagnostics(file_id)",
+        // files with bad dates were indexed without reporting anything
+        "UPDATE docs SET indexed = NULL",
+    ],
+    // 11: workspaces. Existing docs are picked up when their workspace is registered in `init`
+    &[
+        r#"CREATE TABLE workspaces
+        (id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        path TEXT NOT NULL)"#,
+        "ALTER TABLE docs ADD COLUMN workspace_id INTEGER REFERENCES workspaces(id)",
+        "CREATE INDEX docs_workspace ON docs(workspace_id)",
+    ],
+    // 12: content hashes. Docs without a hash are parsed again the next time they're indexed
+    &[
+        "ALTER TABLE docs ADD COLUMN hash TEXT",
+        "ALTER TABLE docs ADD COLUMN mtime INTEGER",
+    ],
+    // 13: persistent views from config, so they can be dropped once they're removed from it
+    &["CREATE TABLE user_views (name TEXT PRIMARY KEY)"],
+    // 14: every date is stored as UTC text, see `norg_date::SQL_FORMAT`. Task dates were unix
+    // timestamps, doc dates were whatever was written in the metadata, so those are parsed again
+    &[
+        "UPDATE tasks SET due = datetime(due, 'unixepoch') WHERE typeof(due) = 'integer'",
+        "UPDATE tasks SET starts = datetime(starts, 'unixepoch') WHERE typeof(starts) = 'integer'",
+        "UPDATE tasks SET recurs = datetime(recurs, 'unixepoch') WHERE typeof(recurs) = 'integer'",
+        "UPDATE tasks SET timestamp = datetime(timestamp, 'unixepoch')
+         WHERE typeof(timestamp) = 'integer'",
+        "UPDATE task_events SET old_value = datetime(old_value, 'unixepoch')
+         WHERE field IN ('due', 'starts') AND typeof(old_value) = 'integer'",
+        "UPDATE task_events SET new_value = datetime(new_value, 'unixepoch')
+         WHERE field IN ('due', 'starts') AND typeof(new_value) = 'integer'",
+        "UPDATE docs SET indexed = NULL",
+    ],
+    // 15: settings that change what's stored, so we can tell when they change between runs
+    &["CREATE TABLE settings (key TEXT PRIMARY KEY, value)"],
+];
+
+/// Apply any migrations that this database hasn't seen yet. If the database was written by a newer
+/// version of this plugin (or we otherwise don't understand its version), we can't know what its
+/// schema looks like, so we drop everything and rebuild it from scratch. The database only holds
+/// information that we can get back by indexing again, so this doesn't lose anything. Run inside
+/// of a [transaction], so a migration that fails leaves the database how it was
+async fn migrate(conn: &Connection) -> anyhow::Result<()> {
+    let latest = MIGRATIONS.len() as i64;
+    let mut version = user_version(conn).await?;
+
+    if !(0..=latest).contains(&version) {
+        warn!("Unknown schema version {version} (latest is {latest}), rebuilding the database");
+        drop_schema(conn).await?;
+        version = 0;
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Applying migration {}", i + 1);
+        for statement in migration.iter() {
+            if let Err(e) = conn.execute(statement, ()).await {
+                bail!("Migration {} failed: {e}", i + 1);
+            }
+        }
+        // pragmas don't take parameters
+        conn.execute(&format!("PRAGMA user_version = {}", i + 1), ()).await?;
+    }
+
+    Ok(())
+}
+
+async fn user_version(conn: &Connection) -> anyhow::Result<i64> {
+    let mut rows = conn.query("PRAGMA user_version", ()).await?;
+    match rows.next().await? {
+        Some(row) => Ok(row.get(0)?),
+        None => bail!("PRAGMA user_version returned nothing"),
+    }
+}
+
+/// Drop every table, view, and trigger in the database and reset the schema version
+async fn drop_schema(conn: &Connection) -> anyhow::Result<()> {
+    // virtual tables go first, they take their shadow tables with them
+    let mut rows = conn
+        .query(
+            "SELECT type, name FROM sqlite_master
+             WHERE type IN ('table', 'view', 'trigger') AND name NOT LIKE 'sqlite_%'
+             ORDER BY sql LIKE 'CREATE VIRTUAL%' DESC",
+            (),
+        )
+        .await?;
+
+    let mut objects: Vec<(String, String)> = vec![];
+    while let Some(row) = rows.next().await? {
+        objects.push((row.get(0)?, row.get(1)?));
+    }
+
+    for (kind, name) in objects {
+        conn.execute(&format!("DROP {kind} IF EXISTS \"{name}\""), ()).await?;
+    }
+    conn.execute("PRAGMA user_version = 0", ()).await?;
+
+    Ok(())
+}
+
+/// Write the doc, its categories, and its tasks. Doesn't manage transactions, callers are
+/// responsible for that
+async fn write_doc(conn: &Connection, doc: &mut ParsedDocument) -> anyhow::Result<i64> {
+    let mut rows = conn.query(
+        "INSERT INTO docs (path, title, description, authors, created, updated, line_count, hash, mtime, workspace_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9,
+            -- the innermost workspace that the doc is in
+            (SELECT id FROM workspaces WHERE substr(?1, 1, length(path) + 1) = path || '/'
+             ORDER BY length(path) DESC LIMIT 1))
+         ON CONFLICT(path) DO UPDATE SET title=excluded.title, description=excluded.description, authors=excluded.authors, updated=excluded.updated, created=excluded.created, line_count=excluded.line_count, hash=excluded.hash, mtime=excluded.mtime, workspace_id=excluded.workspace_id
+         RETURNING id",
+        doc.doc_params(),
+    ).await?;
+
+    let row = rows.next().await?;
+    if let Some(row) = row {
+        let id = *row
+            .get_value(0)?
+            .as_integer()
+            .ok_or(anyhow::anyhow!("ID isn't an int"))?;
+
+        conn.execute("DELETE FROM categories WHERE file_id = ?1", [id])
+            .await?;
+
+        // push new categories
+        if !doc.categories.is_empty() {
+            let values = (0..doc.categories.len())
+                .map(|i| format!("(?{}, ?1)", i + 2))
+                .collect_vec()
+                .join(",");
+            let cat_query = format!("INSERT INTO categories (name, file_id) VALUES {values}");
+            let mut params = doc.categories.clone();
+            params.insert(0, id.to_string());
+
+            conn.execute(&cat_query, params).await?;
+        }
+
+        add_tasks(conn, doc, id).await?;
+        add_links(conn, doc, id).await?;
+        add_paragraphs(conn, doc, id).await?;
+        add_diagnostics(conn, doc, id).await?;
+
+        Ok(id)
+    } else {
+        bail!("Failed to fetch ID")
+    }
+}
+
+/// Write the doc inside of a savepoint. The outer result is an error only if we failed to manage
+/// the savepoint itself, the inner result is the result of writing the doc
+async fn write_doc_savepoint(
+    conn: &Connection,
+    doc: &mut ParsedDocument,
+) -> anyhow::Result<anyhow::Result<i64>> {
+    conn.execute("SAVEPOINT doc", ()).await?;
+    let res = write_doc(conn, doc).await;
+    if res.is_err() {
+        conn.execute("ROLLBACK TO doc", ()).await?;
+    }
+    conn.execute("RELEASE doc", ()).await?;
+    Ok(res)
+}
+
+/// Delete the doc along with everything that references it
+async fn remove_doc(conn: &Connection, id: i64) -> anyhow::Result<()> {
+    conn.execute("DELETE FROM tasks WHERE file_id = ?1", [id]).await?;
+    conn.execute("DELETE FROM task_events WHERE file_id = ?1", [id]).await?;
+    conn.execute("DELETE FROM links WHERE file_id = ?1", [id]).await?;
+    conn.execute("DELETE FROM body WHERE file_id = ?1", [id]).await?;
+    conn.execute("DELETE FROM diagnostics WHERE file_id = ?1", [id]).await?;
+    conn.execute("DELETE FROM categories WHERE file_id = ?1", [id]).await?;
+    conn.execute("DELETE FROM docs WHERE id = ?1", [id]).await?;
+    Ok(())
+}
+
+/// A task as it's currently stored, the parts we need to match it up with a parsed task
 #[derive(Deserialize)]
-struct TaskCreated {
+struct StoredTask {
+    task_id: i64,
+    parent_id: Option<i64>,
     text: String,
-    // this is a date, but we can treat it like a string for serialization purposes, and b/c we're
-    // not manipulating it at all
+    status: String,
+    // dates are compared in their stored form, see [norg_date::SQL_FORMAT]
+    due: Option<String>,
+    starts: Option<String>,
+    timestamp: Option<String>,
+    // the rule rather than `recurs`, which moves forward on its own as time passes
+    recurrence: Option<String>,
+    priority: Option<String>,
+    start_line: Option<i64>,
     created: String,
-    // same ^
-    updated: String,
+    updated: Option<String>,
 }
 
-/// 1. fetch existing tasks for this file
-/// 2. compare text between existing and new tasks, if they're the same, use the `created` date
-///    from the existing task in the new task.
+impl StoredTask {
+    /// Texts of the tasks this one is nested under, outermost first
+    fn parents(&self, by_id: &HashMap<i64, &StoredTask>) -> Vec<String> {
+        let mut parents = vec![];
+        let mut parent = self.parent_id;
+        // the length check is just in case the parent ids loop somehow
+        while let Some(p) = parent.and_then(|id| by_id.get(&id)) {
+            if parents.len() > by_id.len() {
+                break;
+            }
+            parents.push(p.text.clone());
+            parent = p.parent_id;
+        }
+        parents.reverse();
+        parents
+    }
+
+    /// Whether the parsed task has a different status, dates or recurrence than this one. `recurs`
+    /// is left out, it's recomputed from the current time on every parse
+    fn changed(&self, task: &Task) -> bool {
+        let ts = |d: Option<DateTime<Utc>>| d.map(norg_date::to_sql);
+        self.status != task.status
+            || self.due != ts(task.due)
+            || self.starts != ts(task.starts)
+            || self.timestamp != ts(task.timestamp)
+            || self.recurrence != task.recurrence
+    }
+
+    /// `(field, old value, new value)` for each of the tracked fields that the parsed task changed
+    fn events(&self, task: &Task) -> Vec<(&'static str, Value, Value)> {
+        let mut events = vec![];
+        if self.status != task.status {
+            events.push(("status", self.status.clone().into(), task.status.clone().into()));
+        }
+        for (field, old, new) in [
+            ("due", self.due.clone(), task.due),
+            ("starts", self.starts.clone(), task.starts),
+        ] {
+            let new = new.map(norg_date::to_sql);
+            if old != new {
+                events.push((field, old.into(), new.into()));
+            }
+        }
+        if self.priority != task.priority {
+            events.push(("priority", self.priority.clone().into(), task.priority.clone().into()));
+        }
+        events
+    }
+}
+
+/// Keys for the tasks (and their children) in the order that [insert_tasks] visits them
+fn task_keys(tasks: &[Task], parents: &mut Vec<String>, keys: &mut Vec<TaskKey>) {
+    for task in tasks {
+        keys.push(TaskKey {
+            text: task.text.clone(),
+            parents: parents.clone(),
+            line: task.start_line,
+        });
+        parents.push(task.text.clone());
+        task_keys(&task.children, parents, keys);
+        parents.pop();
+    }
+}
+
+/// Replace the tasks stored for this doc. The parsed tasks are matched up with the stored ones
+/// (see [task_match]). Matched tasks keep their id and `created` date, and they keep their
+/// `updated` date too unless their status or dates changed
 async fn add_tasks(
     conn: &Connection,
     doc: &mut ParsedDocument,
@@ -184,72 +1157,197 @@ async fn add_tasks(
 ) -> anyhow::Result<()> {
     let mut rows = conn
         .query(
-            "SELECT text, created, updated FROM tasks WHERE file_id = ?1",
+            "SELECT task_id, parent_id, text, status, due, starts, timestamp, recurrence,
+                priority, start_line, created, updated
+             FROM tasks WHERE file_id = ?1",
             params![doc_id],
         )
         .await?;
 
-    let mut tasks: Vec<TaskCreated> = Vec::new();
+    let mut stored: Vec<StoredTask> = Vec::new();
     while let Ok(Some(row)) = rows.next().await {
         if let Ok(task) = libsql::de::from_row(&row) {
-            tasks.push(task);
+            stored.push(task);
         };
     }
 
-    conn.execute("DELETE FROM tasks WHERE file_id = ?1", params![doc_id]).await?;
-
-    // recurse through tasks, and search for matching text.
-    // any that have the same text will keep their updated field
-    async fn update_tasks(
-        conn: &Connection,
-        tasks: &mut Vec<Task>,
-        existing_tasks: &Vec<TaskCreated>,
-        parent: Option<i64>,
-        file_id: i64,
-    ) -> anyhow::Result<()> {
-        for task in tasks {
-            task.parent_id = parent;
-            task.file_id = Some(file_id);
-            for et in existing_tasks {
-                if et.text == task.text {
-                    task.created = Some(et.created.clone());
-                    task.updated = Some(et.updated.clone());
+    let by_id: HashMap<i64, &StoredTask> = stored.iter().map(|t| (t.task_id, t)).collect();
+    let old_keys = stored
+        .iter()
+        .map(|t| TaskKey {
+            text: t.text.clone(),
+            parents: t.parents(&by_id),
+            line: t.start_line,
+        })
+        .collect_vec();
+    let mut new_keys = vec![];
+    task_keys(&doc.tasks, &mut vec![], &mut new_keys);
+    let matches = task_match::match_tasks(&old_keys, &new_keys)
+        .into_iter()
+        .map(|m| m.map(|o| &stored[o]))
+        .collect_vec();
+
+    // matched tasks are overwritten in place, so they keep their ids. Everything else goes, along
+    // with its history. Ids can be reused, a new task shouldn't inherit a deleted one's events
+    let kept = serde_json::to_string(&matches.iter().flatten().map(|t| t.task_id).collect_vec())?;
+    conn.execute(
+        "DELETE FROM task_events WHERE task_id IN (SELECT task_id FROM tasks
+            WHERE file_id = ?1 AND task_id NOT IN (SELECT value FROM json_each(?2)))",
+        params![doc_id, kept.clone()],
+    )
+    .await?;
+    conn.execute(
+        "DELETE FROM tasks
+         WHERE file_id = ?1 AND task_id NOT IN (SELECT value FROM json_each(?2))",
+        params![doc_id, kept],
+    )
+    .await?;
+
+    insert_tasks(conn, &mut doc.tasks, &mut matches.into_iter(), None, doc_id).await
+}
+
+async fn insert_tasks(
+    conn: &Connection,
+    tasks: &mut [Task],
+    matches: &mut std::vec::IntoIter<Option<&StoredTask>>,
+    parent: Option<i64>,
+    file_id: i64,
+) -> anyhow::Result<()> {
+    for task in tasks {
+        task.parent_id = parent;
+        task.file_id = Some(file_id);
+        let events = match matches.next().flatten() {
+            Some(old) => {
+                task.task_id = Some(old.task_id);
+                task.created = Some(old.created.clone());
+                if !old.changed(task) {
+                    task.updated = old.updated.clone();
                 }
+                old.events(task)
             }
-            // I hate this more than you do. I promise. I hate all of this code.
-            // I've considered switching off of SQLite b/c of some of this code. (I should really
-            // just use diesel but that feels so heavy).
-            let mut rows = conn.query(
-                "INSERT INTO tasks
-                    (text,
-                    status,
-                    due,
-                    starts,
-                    recurs,
-                    priority,
-                    timestamp,
-                    parent_id,
-                    created,
-                    file_id)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-                 RETURNING task_id",
-                task.task_params(),
-            ).await?;
-            let row = rows.next().await?;
-            if let Some(row) = row {
-                let id = *row
-                    .get_value(0)?
-                    .as_integer()
-                    .ok_or(anyhow::anyhow!("ID isn't an int"))?;
-                Box::pin(update_tasks(conn, &mut task.children, existing_tasks, Some(id), file_id)).await?;
-            } else {
-                error!("Failed to get ID for task {}, stopping. Children will not be added", task.text);
+            // new tasks start their history with their first status
+            None => vec![("status", Value::Null, Value::Text(task.status.clone()))],
+        };
+        // I hate this more than you do. I promise. I hate all of this code.
+        // I've considered switching off of SQLite b/c of some of this code. (I should really
+        // just use diesel but that feels so heavy).
+        let mut rows = conn.query(
+            "INSERT INTO tasks
+                (text,
+                status,
+                due,
+                starts,
+                recurs,
+                priority,
+                timestamp,
+                parent_id,
+                created,
+                file_id,
+                kind,
+                level,
+                start_line,
+                end_line,
+                start_col,
+                updated,
+                task_id,
+                recurrence)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15,
+                COALESCE(?16, CURRENT_TIMESTAMP), ?17, ?18)
+             ON CONFLICT(task_id) DO UPDATE SET
+                text=excluded.text, status=excluded.status, due=excluded.due,
+                starts=excluded.starts, recurs=excluded.recurs, priority=excluded.priority,
+                timestamp=excluded.timestamp, parent_id=excluded.parent_id,
+                created=excluded.created, file_id=excluded.file_id, kind=excluded.kind,
+                level=excluded.level, start_line=excluded.start_line, end_line=excluded.end_line,
+                start_col=excluded.start_col, updated=excluded.updated,
+                recurrence=excluded.recurrence
+             RETURNING task_id",
+            task.task_params(),
+        ).await?;
+        let row = rows.next().await?;
+        if let Some(row) = row {
+            let id = *row
+                .get_value(0)?
+                .as_integer()
+                .ok_or(anyhow::anyhow!("ID isn't an int"))?;
+            for (field, old_value, new_value) in events {
+                conn.execute(
+                    "INSERT INTO task_events (task_id, file_id, field, old_value, new_value)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![id, file_id, field, old_value, new_value],
+                )
+                .await?;
             }
+            Box::pin(insert_tasks(conn, &mut task.children, matches, Some(id), file_id)).await?;
+        } else {
+            error!("Failed to get ID for task {}, stopping. Children will not be added", task.text);
+            // keep the matches lined up with the tasks we've yet to visit
+            let mut skipped = vec![];
+            task_keys(&task.children, &mut vec![], &mut skipped);
+            matches.by_ref().take(skipped.len()).for_each(drop);
         }
-        Ok(())
     }
+    Ok(())
+}
+
+/// Replace the links stored for this doc
+async fn add_links(conn: &Connection, doc: &ParsedDocument, doc_id: i64) -> anyhow::Result<()> {
+    conn.execute("DELETE FROM links WHERE file_id = ?1", params![doc_id]).await?;
+
+    for link in &doc.links {
+        conn.execute(
+            "INSERT INTO links
+                (file_id,
+                line,
+                kind,
+                target_path,
+                target_heading,
+                resolved_path,
+                source_heading)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            link.link_params(doc_id),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
 
-    update_tasks(conn, &mut doc.tasks, &tasks, None, doc_id).await?;
+/// Replace the paragraphs stored in the full text index for this doc
+async fn add_paragraphs(
+    conn: &Connection,
+    doc: &ParsedDocument,
+    doc_id: i64,
+) -> anyhow::Result<()> {
+    conn.execute("DELETE FROM body WHERE file_id = ?1", params![doc_id]).await?;
+
+    for paragraph in &doc.paragraphs {
+        conn.execute(
+            "INSERT INTO body (text, heading, line, file_id) VALUES (?1, ?2, ?3, ?4)",
+            paragraph.paragraph_params(doc_id),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Replace the diagnostics stored for this doc
+async fn add_diagnostics(
+    conn: &Connection,
+    doc: &ParsedDocument,
+    doc_id: i64,
+) -> anyhow::Result<()> {
+    conn.execute("DELETE FROM diagnostics WHERE file_id = ?1", params![doc_id]).await?;
+
+    for diagnostic in &doc.diagnostics {
+        conn.execute(
+            "INSERT INTO diagnostics (file_id, line, kind, text, message)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            diagnostic.diagnostic_params(doc_id),
+        )
+        .await?;
+    }
 
     Ok(())
 }
@@ -257,6 +1355,15 @@ async fn add_tasks(
 pub mod util {
     use libsql::Row;
 
+    /// A fresh, empty directory for a test to put its database and files in
+    #[cfg(test)]
+    pub fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("neorq-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
     /// Get a string value from a column, checking the types along the way
     pub fn gets_checked(row: &Row, column: i32) -> Option<String> {
         match row.column_type(column) {
@@ -269,3 +1376,247 @@ pub mod util {
         }
     }
 }
+
+#[tokio::test]
+async fn failed_transactions_roll_back() {
+    let dir = util::test_dir("transaction");
+    let db = DatabaseConnection::new(&dir.join("test.sqlite")).await.unwrap();
+    let conn = &db.conn;
+
+    let res = transaction::<(), _, _>(conn, move || async move {
+        conn.execute("INSERT INTO workspaces (name, path) VALUES ('a', '/a')", ())
+            .await?;
+        bail!("something went wrong")
+    })
+    .await;
+    assert!(res.is_err());
+
+    // the connection isn't stuck in the failed transaction, and nothing from it was kept
+    db.register_workspace("b", Path::new("/b")).await.unwrap();
+    let mut rows = conn.query("SELECT name FROM workspaces", ()).await.unwrap();
+    let mut names: Vec<String> = vec![];
+    while let Some(row) = rows.next().await.unwrap() {
+        names.push(row.get(0).unwrap());
+    }
+    assert_eq!(names, ["b"]);
+}
+
+#[tokio::test]
+async fn migrate_and_rebuild() {
+    let db = Builder::new_local(":memory:").build().await.unwrap();
+    let conn = db.connect().unwrap();
+    let latest = MIGRATIONS.len() as i64;
+    let count = |sql: &'static str| {
+        let conn = conn.clone();
+        async move {
+            let mut rows = conn.query(sql, ()).await.unwrap();
+            rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap()
+        }
+    };
+
+    transaction(&conn, || migrate(&conn)).await.unwrap();
+    assert_eq!(user_version(&conn).await.unwrap(), latest);
+    // nothing left to apply the second time
+    transaction(&conn, || migrate(&conn)).await.unwrap();
+    assert_eq!(user_version(&conn).await.unwrap(), latest);
+
+    // a database written by a newer version is rebuilt from scratch
+    conn.execute("INSERT INTO workspaces (name, path) VALUES ('a', '/a')", ())
+        .await
+        .unwrap();
+    conn.execute("CREATE TABLE from_the_future (id INTEGER)", ())
+        .await
+        .unwrap();
+    conn.execute(&format!("PRAGMA user_version = {}", latest + 1), ())
+        .await
+        .unwrap();
+    transaction(&conn, || migrate(&conn)).await.unwrap();
+    assert_eq!(user_version(&conn).await.unwrap(), latest);
+    assert_eq!(count("SELECT count(*) FROM workspaces").await, 0);
+    assert_eq!(
+        count("SELECT count(*) FROM sqlite_master WHERE name = 'from_the_future'").await,
+        0
+    );
+}
+
+#[tokio::test]
+async fn full_text_search() {
+    let dir = util::test_dir("search");
+    let db = DatabaseConnection::new(&dir.join("test.sqlite")).await.unwrap();
+    let source = "* Fruit\n  Bananas are yellow.\n** Apples\n   They're red.\n";
+    let parse = |path| ParsedDocument::from_source(path, source, &Default::default());
+    let hits = |query: &'static str| {
+        let db = db.clone();
+        async move {
+            let mut rows = db.search(query, 10, Some("notes")).await.unwrap();
+            let mut hits: Vec<(String, Option<String>)> = vec![];
+            while let Some(row) = rows.next().await.unwrap() {
+                hits.push((row.get(0).unwrap(), row.get(2).unwrap()));
+            }
+            hits
+        }
+    };
+
+    db.register_workspace("notes", Path::new("/notes")).await.unwrap();
+    db.register_workspace("other", Path::new("/other")).await.unwrap();
+    db.set_full_text(true).await.unwrap();
+    // the other workspace's copy doesn't show up
+    db.insert_or_update_doc(&mut parse("/notes/fruit.norg")).await.unwrap();
+    db.insert_or_update_doc(&mut parse("/other/fruit.norg")).await.unwrap();
+    let path = "/notes/fruit.norg".to_string();
+    assert_eq!(hits("red").await, [(path.clone(), Some("Fruit > Apples".to_string()))]);
+    assert_eq!(hits("banana*").await, [(path, Some("Fruit".to_string()))]);
+
+    // turning it off clears the paragraphs, turning it back on has every doc parsed again
+    db.set_full_text(false).await.unwrap();
+    assert!(hits("red").await.is_empty());
+    db.set_full_text(true).await.unwrap();
+    assert!(db.doc_states(Path::new("/")).await.unwrap().values().all(|s| s.stale));
+}
+
+#[tokio::test]
+async fn task_events() {
+    let dir = util::test_dir("task_events");
+    let db = DatabaseConnection::new(&dir.join("test.sqlite")).await.unwrap();
+    let write = |source: &'static str| {
+        let db = db.clone();
+        async move {
+            let mut doc = ParsedDocument::from_source("/notes/t.norg", source, &Default::default());
+            db.insert_or_update_doc(&mut doc).await.unwrap();
+        }
+    };
+    // (task text, field, old value, new value) for every event, oldest first
+    let events = || {
+        let db = db.clone();
+        async move {
+            let mut rows = db
+                .user_query(
+                    "SELECT t.text, e.field, e.old_value, e.new_value FROM task_events e
+                     JOIN tasks t ON t.task_id = e.task_id ORDER BY e.id",
+                    (),
+                )
+                .await
+                .unwrap();
+            let mut events: Vec<(String, String, Option<String>, Option<String>)> = vec![];
+            while let Some(row) = rows.next().await.unwrap() {
+                events.push((
+                    row.get(0).unwrap(),
+                    row.get(1).unwrap(),
+                    row.get(2).unwrap(),
+                    row.get(3).unwrap(),
+                ));
+            }
+            events
+        }
+    };
+    let event = |text: &str, field: &str, old: Option<&str>, new: &str| {
+        (text.to_string(), field.to_string(), old.map(String::from), Some(new.to_string()))
+    };
+
+    write("* ( ) keep\n* ( ) drop\n").await;
+    write("* (x) keep\n").await;
+    // `drop` was the newest task, so a new one could get its id
+    write("* (x) keep\n* ( ) new\n").await;
+    assert_eq!(
+        events().await,
+        [
+            event("keep", "status", None, "Undone"),
+            event("keep", "status", Some("Undone"), "Done"),
+            event("new", "status", None, "Undone"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn scoped_workspaces() {
+    let dir = util::test_dir("workspaces");
+    let db = DatabaseConnection::new(&dir.join("test.sqlite")).await.unwrap();
+    let count = |workspace: &'static str| {
+        let db = db.clone();
+        async move {
+            let mut rows = db
+                .workspace_query("SELECT count(*) FROM docs", (), Some(workspace))
+                .await
+                .unwrap();
+            rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap()
+        }
+    };
+    let write = |path: &'static str| {
+        let db = db.clone();
+        async move {
+            let mut doc = ParsedDocument::from_source(path, "* hi\n", &Default::default());
+            db.insert_or_update_doc(&mut doc).await.unwrap();
+        }
+    };
+
+    write("/a/one.norg").await;
+    write("/b/two.norg").await;
+    let a = db.register_workspace("a", Path::new("/a")).await.unwrap();
+    db.register_workspace("b", Path::new("/b")).await.unwrap();
+    assert_eq!((count("a").await, count("b").await), (1, 1));
+    // the cached connection sees docs written after it was made
+    write("/a/three.norg").await;
+    assert_eq!(count("a").await, 2);
+
+    // registering it again in the same place is a no-op, moving it picks up the docs there and
+    // lets go of the ones it left behind
+    assert_eq!(db.register_workspace("a", Path::new("/a/")).await.unwrap(), a);
+    assert_eq!(db.register_workspace("a", Path::new("/b")).await.unwrap(), a);
+    assert_eq!((count("a").await, count("b").await), (1, 0));
+}
+
+#[tokio::test]
+async fn views_are_only_replaced_when_they_change() {
+    let dir = util::test_dir("views");
+    let file = dir.join("test.sqlite");
+    // two neovims opening the same database at once
+    let (a, b) = tokio::join!(DatabaseConnection::new(&file), DatabaseConnection::new(&file));
+    let mut db = a.unwrap();
+    drop(b.unwrap());
+
+    let views = |list: &[(&str, &str)]| -> HashMap<String, String> {
+        list.iter().map(|(n, s)| (n.to_string(), s.to_string())).collect()
+    };
+
+    let config = views(&[("titles", "SELECT title FROM docs"), ("paths", "SELECT path FROM docs")]);
+    assert!(db.create_views(&config, false).await.unwrap().is_empty());
+    let titles = sql_of(&file, "titles").await.unwrap();
+
+    // a broken replacement keeps the last good version, and a removed view is dropped
+    let config = views(&[("titles", "SELECT nope FROM docs")]);
+    let errors = db.create_views(&config, false).await.unwrap();
+    assert_eq!(errors.keys().collect_vec(), ["titles"]);
+    assert_eq!(sql_of(&file, "titles").await, Some(titles.clone()));
+    assert_eq!(sql_of(&file, "paths").await, None);
+    // and still only sees one workspace at a time
+    for path in ["/a/one.norg", "/b/two.norg"] {
+        let mut doc = ParsedDocument::from_source(path, "* hi\n", &Default::default());
+        db.insert_or_update_doc(&mut doc).await.unwrap();
+    }
+    db.register_workspace("a", Path::new("/a")).await.unwrap();
+    let mut rows = db
+        .workspace_query("SELECT count(*) FROM titles", (), Some("a"))
+        .await
+        .unwrap();
+    assert_eq!(rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap(), 1);
+    drop(rows);
+
+    // opening it again leaves everything that's already right alone
+    let open_tasks = sql_of(&file, "open_tasks").await;
+    assert!(open_tasks.is_some());
+    drop(db);
+    DatabaseConnection::new(&file).await.unwrap();
+    assert_eq!(sql_of(&file, "open_tasks").await, open_tasks);
+    assert_eq!(sql_of(&file, "titles").await, Some(titles));
+}
+
+/// The statement that `name` was made with
+#[cfg(test)]
+async fn sql_of(file: &Path, name: &str) -> Option<String> {
+    let conn = Builder::new_local(file).build().await.unwrap().connect().unwrap();
+    let mut rows = conn
+        .query("SELECT sql FROM sqlite_master WHERE name = ?1", [name])
+        .await
+        .unwrap();
+    rows.next().await.unwrap().map(|row| row.get::<String>(0).unwrap())
+}
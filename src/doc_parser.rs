@@ -3,14 +3,19 @@ use itertools::Itertools;
 use libsql::params::IntoParams;
 use libsql::params;
 use log::{trace, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use rust_norg::metadata::{parse_metadata, NorgMeta};
-use rust_norg::{parse_tree, ParagraphSegment, ParagraphSegmentToken};
-use rust_norg::{DetachedModifierExtension, NorgAST};
+use rust_norg::{parse_tree, LinkTarget, ParagraphSegment, ParagraphSegmentToken};
+use rust_norg::{DetachedModifierExtension, NestableDetachedModifier, NorgAST};
+use std::collections::HashMap;
 use std::io;
+use std::path::{Component, Path, PathBuf};
 
 use std::fs;
+use xxhash_rust::xxh3::xxh3_64;
 
-use crate::norg_date;
+use crate::norg_date::{self, DateOptions, Strictness};
 
 // pub struct TaskItem {
 //     content: String,
@@ -27,23 +32,84 @@ pub struct ParsedDocument {
     pub authors: Vec<String>,
     pub created_date: Option<String>,
     pub updated_date: Option<String>,
-    // TODO: parse document body too?
-    // / Paragraphs, untouched, still newlines, markup, links, etc.
-    // / We will want a stripped version for searching against
-    // pub paragraphs: Vec<String>,
-    // // how do we want to store links? Should we just use the Neorg structure?
-    // pub links: Vec<String>,
-    /// Heading tasks only for now.
+    /// number of lines in the file
+    pub line_count: i64,
+    /// [content_hash] of the file, this is what decides whether a file needs to be parsed again
+    pub hash: String,
+    /// modified time of the file in ms, None if we don't know it. Only used to skip hashing files
+    /// that haven't been touched
+    pub mtime: Option<i64>,
+    /// Plain text of every paragraph, for full text search
+    pub paragraphs: Vec<Paragraph>,
+    /// Every link and anchor in the document, in document order
+    pub links: Vec<Link>,
+    /// Heading and list item tasks, nested under the task that they're in
     pub tasks: Vec<Task>,
+    /// Problems we ran into while parsing, see [Diagnostic]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Link {
+    /// 1-indexed line the link is on, None if we couldn't find it in the source
+    pub line: Option<i64>,
+    /// What the link points to: `file`, `heading`, `url`, `external` (a non-norg file),
+    /// `footnote`, `definition`, `generic`, `wiki`, `extendable`, `timestamp`, or `anchor` (an
+    /// anchor without a definition in this document)
+    pub kind: String,
+    /// file path or url, exactly as it's written in the link. None for links within the document
+    pub target_path: Option<String>,
+    /// heading title or other named target within the file
+    pub target_heading: Option<String>,
+    /// absolute path of the file that the link points to, see [ParsedDocument::resolve_links]
+    pub resolved_path: Option<String>,
+    /// title of the heading that the link is under
+    pub source_heading: Option<String>,
+}
+
+/// Something in the document that we couldn't make sense of
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    /// 1-indexed line, None if we couldn't find it in the source
+    pub line: Option<i64>,
+    /// what we were trying to read: `due`, `starts`, `timestamp`, `recurrence`, or the `created`
+    /// or `updated` metadata
+    pub kind: String,
+    /// the offending text, as it was written
+    pub text: String,
+    /// why we couldn't read it
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Paragraph {
+    /// titles of the headings the paragraph is under, outermost first, joined with ` > `
+    pub heading: Option<String>,
+    /// 1-indexed line the paragraph starts on, None if we couldn't find it in the source
+    pub line: Option<i64>,
+    pub text: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct Task {
     pub text: String,
     pub status: String,
+    /// `heading` or `list`, depending on what the task was written as
+    pub kind: String,
+    /// number of `*` or `-` in front of the task
+    pub level: i64,
+    /// 1-indexed lines the task spans, including its content. None if we couldn't find the task
+    /// in the source
+    pub start_line: Option<i64>,
+    pub end_line: Option<i64>,
+    /// 0-indexed column of the task's `*` or `-`
+    pub start_col: Option<i64>,
     pub due: Option<DateTime<Utc>>,
     pub starts: Option<DateTime<Utc>>,
+    /// next time a recurring task comes around
     pub recurs: Option<DateTime<Utc>>,
+    /// RRULE style rule for recurring tasks, see [norg_date::Recurrence::rule]
+    pub recurrence: Option<String>,
     pub timestamp: Option<DateTime<Utc>>,
     pub priority: Option<String>,
     pub children: Vec<Self>,
@@ -52,23 +118,32 @@ pub struct Task {
     /// this is a string representation of a DateTime
     pub created: Option<String>,
     pub updated: Option<String>,
+    /// id of the stored task that this task was matched up with, None for new tasks
+    pub task_id: Option<i64>,
     pub parent_id: Option<i64>,
     pub file_id: Option<i64>,
 }
 
 impl Task {
-    pub fn new(text: String, status: String) -> Task {
+    pub fn new(text: String, status: String, kind: &str) -> Task {
         Task {
             text,
             status,
+            kind: kind.to_string(),
+            level: 0,
+            start_line: None,
+            end_line: None,
+            start_col: None,
             due: None,
             starts: None,
             recurs: None,
+            recurrence: None,
             priority: None,
             timestamp: None,
             children: vec![],
             created: None,
             updated: None,
+            task_id: None,
             parent_id: None,
             file_id: None,
         }
@@ -77,7 +152,7 @@ impl Task {
     pub fn task_params(
         &self,
     ) -> impl IntoParams {
-        let map_d = |date: DateTime<Utc>| date.timestamp();
+        let map_d = norg_date::to_sql;
         params![
             self.text.clone(),
             self.status.clone(),
@@ -89,14 +164,58 @@ impl Task {
             self.parent_id,
             self.created.clone(),
             self.file_id.expect("Can't call to params without setting file id"),
+            self.kind.clone(),
+            self.level,
+            self.start_line,
+            self.end_line,
+            self.start_col,
+            self.updated.clone(),
+            self.task_id,
+            self.recurrence.clone(),
+        ]
+    }
+
+    fn located(self, level: u16, position: Option<Position>, end_line: Option<i64>) -> Task {
+        Task {
+            level: level as i64,
+            start_line: position.map(|p| p.line),
+            start_col: position.map(|p| p.col),
+            end_line,
+            ..self
+        }
+    }
+}
+
+impl Link {
+    pub fn link_params(&self, file_id: i64) -> impl IntoParams {
+        params![
+            file_id,
+            self.line,
+            self.kind.clone(),
+            self.target_path.clone(),
+            self.target_heading.clone(),
+            self.resolved_path.clone(),
+            self.source_heading.clone(),
         ]
     }
 }
 
+impl Diagnostic {
+    pub fn diagnostic_params(&self, file_id: i64) -> impl IntoParams {
+        params![file_id, self.line, self.kind.clone(), self.text.clone(), self.message.clone()]
+    }
+}
+
+impl Paragraph {
+    pub fn paragraph_params(&self, file_id: i64) -> impl IntoParams {
+        params![self.text.clone(), self.heading.clone(), self.line, file_id]
+    }
+}
+
 impl ParsedDocument {
     pub fn doc_params(&self) -> impl IntoParams {
-        vec![
-            Some(self.path.clone()),
+        params![
+            self.path.clone(),
             self.title.clone(),
             self.description.clone(),
             if self.authors.is_empty() {
@@ -106,8 +225,66 @@ impl ParsedDocument {
             },
             self.created_date.clone(),
             self.updated_date.clone(),
+            self.line_count,
+            self.hash.clone(),
+            self.mtime,
         ]
     }
+
+    /// Resolve the target of each link to an absolute path. `workspace` is the workspace root that
+    /// `$/` links are relative to
+    pub fn resolve_links(&mut self, workspace: Option<&Path>) {
+        let source = Path::new(&self.path);
+        for link in self.links.iter_mut() {
+            link.resolved_path = match (link.kind.as_str(), &link.target_path) {
+                ("url" | "timestamp" | "anchor", _) => None,
+                ("external", Some(p)) => resolve_path(p, source, workspace),
+                (_, Some(p)) => resolve_norg_path(p, source, workspace),
+                (_, None) => Some(source.to_path_buf()),
+            }
+            .map(|p| p.to_string_lossy().to_string());
+        }
+    }
+}
+
+/// Resolve a path the way Neorg does: `$/` is relative to the workspace root, `~/` is relative to
+/// the home directory, and anything else that isn't absolute is relative to the directory of the
+/// file that the link is in. Links into other workspaces (`$name/`) can't be resolved
+pub fn resolve_path(target: &str, source: &Path, workspace: Option<&Path>) -> Option<PathBuf> {
+    let path = if let Some(rest) = target.strip_prefix("$/") {
+        workspace?.join(rest)
+    } else if target.starts_with('$') {
+        return None;
+    } else if let Some(rest) = target.strip_prefix("~/") {
+        dirs::home_dir()?.join(rest)
+    } else {
+        // joining an absolute path replaces the base
+        source.parent()?.join(target)
+    };
+
+    // lexically remove `.` and `..`, the target might not exist
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    Some(normalized)
+}
+
+/// [resolve_path] for links to norg files, which are usually written without their extension
+pub fn resolve_norg_path(target: &str, source: &Path, workspace: Option<&Path>) -> Option<PathBuf> {
+    let path = resolve_path(target, source, workspace)?;
+    if path.extension().is_some_and(|ext| ext == "norg") {
+        return Some(path);
+    }
+    let mut path = path.into_os_string();
+    path.push(".norg");
+    Some(path.into())
 }
 
 pub trait PlainText {
@@ -157,7 +334,7 @@ impl PlainText for ParagraphSegmentToken {
 }
 
 /// fill in the metadata for a document
-fn fill_meta(content: String, doc: &mut ParsedDocument) {
+fn fill_meta(content: String, dates: &DateOptions, doc: &mut ParsedDocument) {
     if let Ok(NorgMeta::Object(meta)) = parse_metadata(&content) {
         let gets = |x: &str| {
             if let Some(NorgMeta::Str(s)) = meta.get(x) {
@@ -169,8 +346,31 @@ fn fill_meta(content: String, doc: &mut ParsedDocument) {
 
         doc.title = gets("title");
         doc.description = gets("description");
-        doc.created_date = gets("created");
-        doc.updated_date = gets("updated");
+        // these are usually written by neorg (`2024-05-01T20:55:35-0500`), not by hand, so
+        // anything that lenient mode reads is fine
+        let lenient = DateOptions {
+            strictness: Strictness::Lenient,
+            ..dates.clone()
+        };
+        let today = dates.timezone.now().date();
+        let mut date = |key: &str| {
+            let text = gets(key)?;
+            match norg_date::parse(&text, today, &lenient) {
+                Ok(d) => Some(norg_date::to_sql(d)),
+                Err(e) => {
+                    warn!("Failed to parse {key} `{text}`: {e}");
+                    doc.diagnostics.push(Diagnostic {
+                        line: None,
+                        kind: key.to_string(),
+                        message: e.to_string(),
+                        text,
+                    });
+                    None
+                }
+            }
+        };
+        doc.created_date = date("created");
+        doc.updated_date = date("updated");
 
         let geta = |x: &str| match meta.get(x) {
             Some(NorgMeta::Array(a)) => a
@@ -194,98 +394,478 @@ fn fill_meta(content: String, doc: &mut ParsedDocument) {
     }
 }
 
-fn examine_heading(
-    _level: u16,
-    title: Vec<ParagraphSegment>,
-    extensions: Vec<DetachedModifierExtension>,
-    content: Vec<NorgAST>,
+/// Tracks our position in the source while we walk the AST. The AST doesn't carry positions, but
+/// we visit nodes in document order, so searching forward from the last thing that we found is
+/// enough to recover line numbers
+struct SourceCursor<'a> {
+    source: &'a str,
+    offset: usize,
+    /// byte offset of the start of each line
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceCursor<'a> {
+    fn new(source: &'a str) -> SourceCursor<'a> {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceCursor {
+            source,
+            offset: 0,
+            line_starts,
+        }
+    }
+
+    /// Find the next occurrence of `needle`, move past it, and return its 1-indexed line. When the
+    /// needle isn't found we stay put, so one miss doesn't throw off everything after it
+    fn find(&mut self, needle: &str) -> Option<i64> {
+        if needle.is_empty() {
+            return None;
+        }
+        let found = self.offset + self.source[self.offset..].find(needle)?;
+        self.offset = found + needle.len();
+        Some(self.line_of(found))
+    }
+
+    /// Find the next heading with exactly `level` stars, see [Self::find]
+    fn find_heading(&mut self, level: u16) -> Option<Position> {
+        static HEADING: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?m)^[ \t]*(\*+)[ \t]").expect("invalid heading regex"));
+        self.find_marker(&HEADING, level)
+    }
+
+    /// Find the next list item (or quote) with exactly `level` markers, see [Self::find]
+    fn find_list_item(&mut self, level: u16) -> Option<Position> {
+        static LIST_ITEM: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?m)^[ \t]*(-+|~+|>+)[ \t]").expect("invalid list item regex")
+        });
+        self.find_marker(&LIST_ITEM, level)
+    }
+
+    /// Find the next match of `re` whose first group is `level` chars long
+    fn find_marker(&mut self, re: &Regex, level: u16) -> Option<Position> {
+        let marker = re
+            .captures_iter(&self.source[self.offset..])
+            .find(|c| c[1].len() == level as usize)?
+            .get(1)?;
+        let found = self.offset + marker.start();
+        self.offset += marker.end();
+
+        let line = self.line_of(found);
+        let col = (found - self.line_starts[line as usize - 1]) as i64;
+        Some(Position { line, col })
+    }
+
+    /// Last line of the heading that starts on `start`. A heading runs until the next heading of
+    /// the same or a higher level, or a `===`
+    fn heading_end(&self, start: i64, level: u16) -> i64 {
+        self.section_end(start, |line| match marker_level(line, &['*']) {
+            Some(l) => l <= level,
+            None => {
+                let line = line.trim();
+                line.len() >= 3 && line.chars().all(|c| c == '=')
+            }
+        })
+    }
+
+    /// Last line of the list item that starts on `start`. A list item runs until a blank line, a
+    /// heading, or the next list item of the same or a higher level
+    fn list_item_end(&self, start: i64, level: u16) -> i64 {
+        self.section_end(start, |line| {
+            line.trim().is_empty()
+                || marker_level(line, &['*']).is_some()
+                || marker_level(line, &['-', '~', '>']).is_some_and(|l| l <= level)
+        })
+    }
+
+    /// The line before the first line after `start` that `ends` the section, trailing blank lines
+    /// aren't included
+    fn section_end(&self, start: i64, ends: impl Fn(&str) -> bool) -> i64 {
+        let last = self.line_count();
+        let mut end = (start + 1..=last)
+            .find(|&n| ends(self.line(n)))
+            .map_or(last, |n| n - 1);
+        while end > start && self.line(end).trim().is_empty() {
+            end -= 1;
+        }
+        end
+    }
+
+    /// Text of the 1-indexed line `n`, without the newline
+    fn line(&self, n: i64) -> &str {
+        let start = self.line_starts[n as usize - 1];
+        let end = self
+            .line_starts
+            .get(n as usize)
+            .map_or(self.source.len(), |next| next - 1);
+        &self.source[start..end]
+    }
+
+    /// Number of lines, a trailing newline doesn't count as starting another one
+    fn line_count(&self) -> i64 {
+        let lines = self.line_starts.len() as i64;
+        if self.source.ends_with('\n') {
+            lines - 1
+        } else {
+            lines
+        }
+    }
+
+    fn line_of(&self, offset: usize) -> i64 {
+        self.line_starts.partition_point(|&start| start <= offset) as i64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    /// 1-indexed
+    line: i64,
+    /// 0-indexed, in bytes
+    col: i64,
+}
+
+/// Length of the run of `markers` that `line` starts with, if it's followed by whitespace
+fn marker_level(line: &str, markers: &[char]) -> Option<u16> {
+    let line = line.trim_start();
+    let first = line.chars().next().filter(|c| markers.contains(c))?;
+    let level = line.chars().take_while(|&c| c == first).count();
+    let rest = &line[level * first.len_utf8()..];
+    rest.starts_with([' ', '\t']).then_some(level as u16)
+}
+
+/// State that's carried through the walk over a single document
+struct ParseContext<'a> {
+    cursor: SourceCursor<'a>,
+    /// anchor definitions seen so far, by name
+    anchors: HashMap<String, Link>,
+    /// index into `doc.links` and name of each anchor that was used. These are resolved against
+    /// the definitions once we've seen the whole document
+    anchor_refs: Vec<(usize, String)>,
+    /// titles of the headings that we're currently inside of
+    headings: Vec<String>,
+    dates: &'a DateOptions,
+}
+
+fn plain_text(segments: &[ParagraphSegment]) -> String {
+    segments.iter().map(PlainText::plain_text).collect()
+}
+
+/// Build a link from the pieces of a [ParagraphSegment::Link]
+fn make_link(filepath: &Option<String>, targets: &[LinkTarget]) -> Link {
+    let (kind, target_path, target_heading) = match targets.first() {
+        None => ("file", filepath.clone(), None),
+        Some(target) => match target {
+            LinkTarget::Heading { title, .. } => {
+                ("heading", filepath.clone(), Some(plain_text(title)))
+            }
+            LinkTarget::Footnote(t) => ("footnote", filepath.clone(), Some(plain_text(t))),
+            LinkTarget::Definition(t) => ("definition", filepath.clone(), Some(plain_text(t))),
+            LinkTarget::Generic(t) => ("generic", filepath.clone(), Some(plain_text(t))),
+            LinkTarget::Wiki(t) => ("wiki", filepath.clone(), Some(plain_text(t))),
+            LinkTarget::Extendable(t) => ("extendable", filepath.clone(), Some(plain_text(t))),
+            LinkTarget::Path(p) => ("external", Some(p.clone()), None),
+            LinkTarget::Url(u) => ("url", Some(u.clone()), None),
+            LinkTarget::Timestamp(_) => ("timestamp", None, None),
+        },
+    };
+
+    Link {
+        line: None,
+        kind: kind.to_string(),
+        target_path,
+        target_heading,
+        resolved_path: None,
+        source_heading: None,
+    }
+}
+
+/// Record the links in a run of paragraph segments
+fn collect_links(
+    segments: &[ParagraphSegment],
+    ctx: &mut ParseContext,
     doc: &mut ParsedDocument,
 ) {
-    let text: String = title.iter().map(|s| s.plain_text()).join("");
+    for segment in segments {
+        match segment {
+            ParagraphSegment::Link {
+                filepath, targets, ..
+            } => {
+                let mut link = make_link(filepath, targets);
+                link.line = link
+                    .target_path
+                    .clone()
+                    .or(link.target_heading.clone())
+                    .and_then(|needle| ctx.cursor.find(&needle));
+                link.source_heading = ctx.headings.last().cloned();
+                doc.links.push(link);
+            }
+            ParagraphSegment::AnchorDefinition { content, target } => {
+                let name = plain_text(content);
+                let line = ctx.cursor.find(&name);
+                if let ParagraphSegment::Link {
+                    filepath, targets, ..
+                } = target.as_ref()
+                {
+                    let mut link = make_link(filepath, targets);
+                    link.line = line;
+                    link.source_heading = ctx.headings.last().cloned();
+                    ctx.anchors.insert(name, link.clone());
+                    doc.links.push(link);
+                }
+            }
+            ParagraphSegment::Anchor { content, .. } => {
+                let name = plain_text(content);
+                ctx.anchor_refs.push((doc.links.len(), name.clone()));
+                doc.links.push(Link {
+                    line: ctx.cursor.find(&name),
+                    kind: String::from("anchor"),
+                    target_path: None,
+                    target_heading: Some(name),
+                    resolved_path: None,
+                    source_heading: ctx.headings.last().cloned(),
+                });
+            }
+            ParagraphSegment::AttachedModifier { content, .. } => {
+                collect_links(content, ctx, doc);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn examine_paragraph(
+    segments: Vec<ParagraphSegment>,
+    ctx: &mut ParseContext,
+    doc: &mut ParsedDocument,
+) {
+    let text = plain_text(&segments);
+
+    // find where the paragraph starts before its links move the cursor past it. If it starts with
+    // a link, the link's line is the paragraph's line
+    let links_before = doc.links.len();
+    let line = match segments.first() {
+        Some(ParagraphSegment::Token(_) | ParagraphSegment::AttachedModifier { .. }) => text
+            .split_whitespace()
+            .next()
+            .and_then(|word| ctx.cursor.find(word)),
+        _ => None,
+    };
+    collect_links(&segments, ctx, doc);
+    let line = line.or_else(|| doc.links.get(links_before).and_then(|l| l.line));
+
+    if !text.trim().is_empty() {
+        doc.paragraphs.push(Paragraph {
+            heading: (!ctx.headings.is_empty()).then(|| ctx.headings.join(" > ")),
+            line,
+            text,
+        });
+    }
+}
+
+/// Build a task out of the extensions on a heading or list item. None if there aren't any
+/// extensions, b/c then it isn't a task
+fn make_task(
+    text: String,
+    kind: &str,
+    extensions: Vec<DetachedModifierExtension>,
+    line: Option<i64>,
+    dates: &DateOptions,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Task> {
+    if extensions.is_empty() {
+        return None;
+    }
 
+    trace!("not empty {text}");
+    trace!("extensions: {extensions:?}");
     // create a task with a temporarily empty status
-    if !extensions.is_empty() {
-        trace!("not empty {text}");
-        trace!("extensions: {extensions:?}");
-        let mut task = Task::new(text, String::from(""));
-        for ext in extensions {
-            match ext {
-                DetachedModifierExtension::Todo(todo_status) => {
-                    // NOTE: Surely there's a better way to do this...
-                    // stack overflow seems to say proc macro.. which I don't want to bother with at
-                    // the moment
-                    task.status = match todo_status {
-                        rust_norg::TodoStatus::Undone => "Undone",
-                        rust_norg::TodoStatus::Done => "Done",
-                        rust_norg::TodoStatus::NeedsClarification => "NeedsClarification",
-                        rust_norg::TodoStatus::Paused => "Paused",
-                        rust_norg::TodoStatus::Urgent => "Urgent",
-                        rust_norg::TodoStatus::Recurring(_) => "Recurring",
-                        rust_norg::TodoStatus::Pending => "Pending",
-                        rust_norg::TodoStatus::Canceled => "Canceled",
+    let mut task = Task::new(text, String::from(""), kind);
+    // dates that leave things out are filled in relative to today
+    let today = dates.timezone.now().date();
+    // modifiers we can't read are left off of the task, and reported
+    let mut report = |kind: &str, text: &str, e: anyhow::Error| {
+        warn!("Failed to parse {kind} `{text}`: {e}");
+        diagnostics.push(Diagnostic {
+            line,
+            kind: kind.to_string(),
+            text: text.to_string(),
+            message: e.to_string(),
+        });
+    };
+    for ext in extensions {
+        match ext {
+            DetachedModifierExtension::Todo(todo_status) => {
+                // NOTE: Surely there's a better way to do this...
+                // stack overflow seems to say proc macro.. which I don't want to bother with at
+                // the moment
+                task.status = match todo_status {
+                    rust_norg::TodoStatus::Undone => "Undone",
+                    rust_norg::TodoStatus::Done => "Done",
+                    rust_norg::TodoStatus::NeedsClarification => "NeedsClarification",
+                    rust_norg::TodoStatus::Paused => "Paused",
+                    rust_norg::TodoStatus::Urgent => "Urgent",
+                    rust_norg::TodoStatus::Recurring(spec) => {
+                        // `(+)` on its own recurs, but we can't say when
+                        if let Some(spec) = spec {
+                            match norg_date::parse_recurrence(&spec) {
+                                Ok(r) => {
+                                    task.recurs = r.next_utc(&dates.timezone);
+                                    task.recurrence = Some(r.rule());
+                                }
+                                Err(e) => report("recurrence", &spec, e),
+                            }
+                        }
+                        "Recurring"
                     }
-                    .to_string()
+                    rust_norg::TodoStatus::Pending => "Pending",
+                    rust_norg::TodoStatus::Canceled => "Canceled",
                 }
-                DetachedModifierExtension::Priority(p) => {
-                    task.priority = Some(p);
-                }
-                DetachedModifierExtension::Timestamp(t) => {
-                    match norg_date::parse(&t) {
-                        Ok(d) => task.timestamp = Some(d),
-                        Err(e) => warn!("Failed to parse timestamp: {e}"),
-                    }
-                }
-                DetachedModifierExtension::DueDate(t) => match norg_date::parse(&t) {
-                    Ok(d) => task.due = Some(d),
-                    Err(e) => warn!("Failed to parse due date: {e}"),
-                },
-                DetachedModifierExtension::StartDate(t) => match norg_date::parse(&t) {
-                    Ok(d) => task.starts = Some(d),
-                    Err(e) => warn!("Failed to parse due date: {e}"),
-                },
+                .to_string()
             }
+            DetachedModifierExtension::Priority(p) => {
+                task.priority = Some(p);
+            }
+            DetachedModifierExtension::Timestamp(t) => match norg_date::parse(&t, today, dates) {
+                Ok(d) => task.timestamp = Some(d),
+                Err(e) => report("timestamp", &t, e),
+            },
+            DetachedModifierExtension::DueDate(t) => match norg_date::parse(&t, today, dates) {
+                Ok(d) => task.due = Some(d),
+                Err(e) => report("due", &t, e),
+            },
+            DetachedModifierExtension::StartDate(t) => match norg_date::parse(&t, today, dates) {
+                Ok(d) => task.starts = Some(d),
+                Err(e) => report("starts", &t, e),
+            },
         }
+    }
 
-        // some really messy logic to nest tasks without having to return anything (b/c this function
-        // will eventually modify the doc in other ways).
-        let before = doc.tasks.len();
-        for node in content {
-            descend(node, doc);
-        }
-        let tasks = doc.tasks.clone();
-        let (existing, nested) = tasks.split_at(before);
-        doc.tasks = existing.to_vec();
+    Some(task)
+}
+
+/// Walk the content of a heading or list item. When the heading/list item is a task, every task
+/// that we find in its content becomes one of its children
+fn nest_tasks(
+    task: Option<Task>,
+    content: Vec<NorgAST>,
+    ctx: &mut ParseContext,
+    doc: &mut ParsedDocument,
+) {
+    // some really messy logic to nest tasks without having to return anything (b/c this function
+    // will eventually modify the doc in other ways).
+    let before = doc.tasks.len();
+    for node in content {
+        descend(node, ctx, doc);
+    }
 
-        task.children = nested.to_vec();
+    if let Some(mut task) = task {
+        task.children = doc.tasks.split_off(before);
         doc.tasks.push(task);
-    } else {
-        for node in content {
-            descend(node, doc);
-        }
     }
 }
 
-fn descend(node: NorgAST, doc: &mut ParsedDocument) {
+fn examine_heading(
+    level: u16,
+    title: Vec<ParagraphSegment>,
+    extensions: Vec<DetachedModifierExtension>,
+    content: Vec<NorgAST>,
+    ctx: &mut ParseContext,
+    doc: &mut ParsedDocument,
+) {
+    let text: String = title.iter().map(|s| s.plain_text()).join("");
+    let position = ctx.cursor.find_heading(level);
+    let end = position.map(|p| ctx.cursor.heading_end(p.line, level));
+    ctx.headings.push(text.clone());
+    collect_links(&title, ctx, doc);
+
+    let line = position.map(|p| p.line);
+    let task = make_task(text, "heading", extensions, line, ctx.dates, &mut doc.diagnostics)
+        .map(|t| t.located(level, position, end));
+    nest_tasks(task, content, ctx, doc);
+    ctx.headings.pop();
+}
+
+fn examine_list_item(
+    level: u16,
+    text: NorgAST,
+    extensions: Vec<DetachedModifierExtension>,
+    content: Vec<NorgAST>,
+    ctx: &mut ParseContext,
+    doc: &mut ParsedDocument,
+) {
+    let title = match &text {
+        NorgAST::Paragraph(segments) => plain_text(segments).trim().to_string(),
+        _ => String::new(),
+    };
+    let position = ctx.cursor.find_list_item(level);
+    let end = position.map(|p| ctx.cursor.list_item_end(p.line, level));
+    descend(text, ctx, doc);
+
+    let line = position.map(|p| p.line);
+    let task = make_task(title, "list", extensions, line, ctx.dates, &mut doc.diagnostics)
+        .map(|t| t.located(level, position, end));
+    nest_tasks(task, content, ctx, doc);
+}
+
+fn descend(node: NorgAST, ctx: &mut ParseContext, doc: &mut ParsedDocument) {
     match node {
         NorgAST::VerbatimRangedTag {
             name,
             parameters: _,
             content,
         } if name.len() == 2 && name[0] == "document" && name[1] == "meta" => {
-            fill_meta(content, doc);
+            fill_meta(content, ctx.dates, doc);
         }
         NorgAST::Heading {
             level,
             title,
             extensions,
             content,
-        } => examine_heading(level, title, extensions, content, doc),
+        } => examine_heading(level, title, extensions, content, ctx, doc),
+        NorgAST::Paragraph(segments) => examine_paragraph(segments, ctx, doc),
+        NorgAST::NestableDetachedModifier {
+            modifier_type,
+            level,
+            extensions,
+            text,
+            content,
+        } => {
+            // quotes can't be tasks
+            let extensions = match modifier_type {
+                NestableDetachedModifier::Quote => vec![],
+                _ => extensions,
+            };
+            examine_list_item(level, *text, extensions, content, ctx, doc);
+        }
+        NorgAST::RangeableDetachedModifier { title, content, .. } => {
+            collect_links(&title, ctx, doc);
+            for node in content {
+                descend(node, ctx, doc);
+            }
+        }
+        NorgAST::CarryoverTag { next_object, .. } => descend(*next_object, ctx, doc),
+        NorgAST::RangedTag { content, .. } => {
+            for node in content {
+                descend(node, ctx, doc);
+            }
+        }
         _ => {}
     }
 }
 
+/// Hash of a file's contents, hex encoded
+pub fn content_hash(contents: &str) -> String {
+    format!("{:016x}", xxh3_64(contents.as_bytes()))
+}
+
 impl ParsedDocument {
-    pub fn new(file_path: &str) -> io::Result<ParsedDocument> {
+    pub fn new(file_path: &str, dates: &DateOptions) -> io::Result<ParsedDocument> {
+        let contents = fs::read_to_string(file_path)?;
+        Ok(ParsedDocument::from_source(file_path, &contents, dates))
+    }
+
+    /// Parse `contents`, which were read from `file_path`
+    pub fn from_source(file_path: &str, contents: &str, dates: &DateOptions) -> ParsedDocument {
         let mut doc = ParsedDocument {
             title: None,
             description: None,
@@ -294,29 +874,158 @@ impl ParsedDocument {
             authors: vec![],
             created_date: None,
             updated_date: None,
+            line_count: 0,
+            hash: content_hash(contents),
+            mtime: None,
+            links: vec![],
             tasks: vec![],
-            // paragraphs: vec![],
-            // links: vec![],
+            paragraphs: vec![],
+            diagnostics: vec![],
         };
-        let contents = fs::read_to_string(file_path)?;
 
-        let ast = parse_tree(&contents);
+        let mut ctx = ParseContext {
+            cursor: SourceCursor::new(contents),
+            anchors: HashMap::new(),
+            anchor_refs: vec![],
+            headings: vec![],
+            dates,
+        };
+        let ast = parse_tree(contents);
         if let Ok(ast) = ast {
             for node in ast {
-                descend(node, &mut doc);
+                descend(node, &mut ctx, &mut doc);
             }
         };
+        doc.line_count = ctx.cursor.line_count();
+
+        // anchors can be used before they're defined
+        for (i, name) in ctx.anchor_refs {
+            if let Some(def) = ctx.anchors.get(&name) {
+                doc.links[i] = Link {
+                    line: doc.links[i].line,
+                    source_heading: doc.links[i].source_heading.clone(),
+                    ..def.clone()
+                };
+            }
+        }
         trace!("{:?}", doc);
-        Ok(doc)
+        doc
     }
 }
 
 #[test]
 fn parse_tasks() {
     // let doc = ParsedDocument::new("/home/benlubas/github/neorg-query/spec/tasks.norg");
-    let doc = ParsedDocument::new("spec/tasks.norg");
+    let doc = ParsedDocument::new("spec/tasks.norg", &DateOptions::default());
     dbg!(&doc);
     assert!(doc.is_ok());
     let doc = doc.unwrap();
-    assert!(doc.tasks.len() == 5);
+    assert!(doc.tasks.len() == 7);
+
+    // list tasks nest under heading tasks, and under other list tasks
+    assert_eq!(doc.tasks[1].children.len(), 1);
+    assert_eq!(doc.tasks[1].children[0].kind, "list");
+    assert_eq!(doc.tasks[5].kind, "list");
+    assert_eq!(doc.tasks[5].children.len(), 1);
+    // list tasks under a list item that isn't a task aren't nested
+    assert_eq!(doc.tasks[6].text, "Nested under a list item that isn't a task");
+
+    assert_eq!(doc.line_count, 39);
+    let task_3 = &doc.tasks[4];
+    assert_eq!(task_3.level, 2);
+    assert_eq!((task_3.start_line, task_3.end_line), (Some(24), Some(32)));
+    assert_eq!(task_3.start_col, Some(0));
+    let list_task = &doc.tasks[1].children[0];
+    assert_eq!(list_task.level, 1);
+    assert_eq!((list_task.start_line, list_task.end_line), (Some(20), Some(20)));
+    assert_eq!(list_task.start_col, Some(3));
+    let nested = &doc.tasks[5].children[0];
+    assert_eq!((nested.start_line, nested.end_line), (Some(37), Some(37)));
+}
+
+#[test]
+fn parse_links() {
+    let doc = ParsedDocument::new("spec/links.norg", &DateOptions::default()).unwrap();
+
+    let kinds = doc.links.iter().map(|l| l.kind.as_str()).collect_vec();
+    // the anchor is resolved to its definition's url
+    assert_eq!(kinds, ["file", "heading", "url", "external", "heading", "url", "url"]);
+
+    let lines = doc.links.iter().map(|l| l.line).collect_vec();
+    assert_eq!(lines, [13, 13, 14, 14, 16, 17, 17].map(Some));
+
+    let mut doc = doc;
+    doc.resolve_links(Some(Path::new("/ws")));
+    let resolved = doc.links.iter().map(|l| l.resolved_path.as_deref()).collect_vec();
+    assert_eq!(
+        resolved[..5],
+        [
+            Some("/ws/notes/other.norg"),
+            Some("spec/tasks.norg"),
+            None,
+            Some("/tmp/file.txt"),
+            Some("spec/links.norg"),
+        ]
+    );
+    assert_eq!(doc.links[4].source_heading.as_deref(), Some("Heading with a link"));
+}
+
+#[test]
+fn parse_diagnostics() {
+    let doc = ParsedDocument::new("spec/diagnostics.norg", &DateOptions::default()).unwrap();
+
+    let found = doc
+        .diagnostics
+        .iter()
+        .map(|d| (d.line, d.kind.as_str(), d.text.trim()))
+        .collect_vec();
+    assert_eq!(
+        found,
+        [
+            (None, "updated", "eventually"),
+            (Some(10), "due", "whenever"),
+            (Some(11), "due", "30th Feb 2025"),
+            (Some(12), "starts", "someday"),
+            (Some(13), "recurrence", "Funday"),
+        ]
+    );
+    assert!(doc.diagnostics.iter().all(|d| !d.message.is_empty()));
+
+    // a task with a bad date is still a task, it just doesn't have the date
+    assert_eq!(doc.tasks.len(), 4);
+    assert_eq!(doc.tasks[0].due, None);
+    assert!(doc.tasks[3].due.is_some());
+}
+
+#[test]
+fn parse_meta_dates() {
+    let source = "@document.meta\ntitle: dates\ncreated: 2024-05-01T20:55:35-0500\n\
+                  updated: sometime\n@end\n";
+    let doc = ParsedDocument::from_source("dates.norg", source, &DateOptions::default());
+
+    assert_eq!(doc.created_date.as_deref(), Some("2024-05-02 01:55:35"));
+    assert_eq!(doc.updated_date, None);
+    assert_eq!(doc.diagnostics.len(), 1);
+    assert_eq!(doc.diagnostics[0].kind, "updated");
+    assert_eq!(doc.diagnostics[0].text, "sometime");
+}
+
+#[test]
+fn parse_paragraphs() {
+    let source = "* Fruit\n  Bananas are yellow.\n\n** Apples\n   They're red.\n\n   And round.\n";
+    let doc = ParsedDocument::from_source("fruit.norg", source, &DateOptions::default());
+
+    let found = doc
+        .paragraphs
+        .iter()
+        .map(|p| (p.heading.as_deref(), p.line, p.text.trim()))
+        .collect_vec();
+    assert_eq!(
+        found,
+        [
+            (Some("Fruit"), Some(2), "Bananas are yellow."),
+            (Some("Fruit > Apples"), Some(5), "They're red."),
+            (Some("Fruit > Apples"), Some(7), "And round."),
+        ]
+    );
 }
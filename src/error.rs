@@ -0,0 +1,155 @@
+// Errors that make it back to lua. Everything in here is an anyhow error up until the point where
+// it's handed back to lua, at that point it gets a kind so that lua can tell what went wrong
+// without having to pick apart the message. Lua hands the error back to `error_info` to get the
+// kind and message out as a table.
+
+use std::fmt;
+
+use mlua::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// `init` hasn't been called, or it failed
+    NotInitialized,
+    /// the database rejected a query, usually a user's `#sql` block
+    SqlError,
+    /// a file couldn't be read, or doesn't exist
+    IoError,
+    /// bad input: options, dates, paths that can't be resolved
+    ParseError,
+    /// anything else, a task panicked or something we didn't expect
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::NotInitialized => "not_initialized",
+            ErrorKind::SqlError => "sql_error",
+            ErrorKind::IoError => "io_error",
+            ErrorKind::ParseError => "parse_error",
+            ErrorKind::Internal => "internal",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NeorqError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl NeorqError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> NeorqError {
+        NeorqError {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_initialized() -> NeorqError {
+        NeorqError::new(ErrorKind::NotInitialized, "call `init` first")
+    }
+
+    /// The NeorqError behind an error that's been through lua, if there is one
+    pub fn from_lua_error(e: &LuaError) -> Option<&NeorqError> {
+        match e {
+            LuaError::ExternalError(e) => e.downcast_ref(),
+            LuaError::CallbackError { cause, .. } | LuaError::WithContext { cause, .. } => {
+                NeorqError::from_lua_error(cause)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `[kind] message`, for `tostring`. Use `error_info` to get at the kind
+impl fmt::Display for NeorqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.kind.as_str(), self.message)
+    }
+}
+
+impl std::error::Error for NeorqError {}
+
+/// Pick a kind based on the errors in the chain. Anything that's already a NeorqError keeps its
+/// kind
+impl From<anyhow::Error> for NeorqError {
+    fn from(e: anyhow::Error) -> NeorqError {
+        let kind = e
+            .chain()
+            .find_map(|cause| {
+                if let Some(e) = cause.downcast_ref::<NeorqError>() {
+                    Some(e.kind)
+                } else if cause.is::<libsql::Error>() {
+                    Some(ErrorKind::SqlError)
+                } else if cause.is::<std::io::Error>()
+                    || cause.is::<notify_debouncer_mini::notify::Error>()
+                {
+                    Some(ErrorKind::IoError)
+                } else if cause.is::<LuaError>()
+                    || cause.is::<serde_json::Error>()
+                    || cause.is::<std::num::ParseIntError>()
+                    || cause.is::<chrono::ParseError>()
+                {
+                    Some(ErrorKind::ParseError)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(ErrorKind::Internal);
+
+        match e.downcast::<NeorqError>() {
+            Ok(e) => e,
+            // `{:#}` includes the causes
+            Err(e) => NeorqError::new(kind, format!("{e:#}")),
+        }
+    }
+}
+
+impl From<NeorqError> for LuaError {
+    fn from(e: NeorqError) -> LuaError {
+        LuaError::external(e)
+    }
+}
+
+/// `{ kind = "sql_error", message = "..." }`
+impl IntoLua for NeorqError {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("kind", self.kind.as_str())?;
+        table.set("message", self.message)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[test]
+fn error_kinds() {
+    let io = anyhow::Error::new(std::io::Error::new(std::io::ErrorKind::NotFound, "gone"));
+    assert_eq!(NeorqError::from(io).kind, ErrorKind::IoError);
+
+    let parse = anyhow::Error::new("x".parse::<i64>().unwrap_err()).context("reading a number");
+    assert_eq!(NeorqError::from(parse).kind, ErrorKind::ParseError);
+
+    let ours = anyhow::Error::new(NeorqError::not_initialized());
+    let e = NeorqError::from(ours);
+    assert_eq!(e.kind, ErrorKind::NotInitialized);
+    assert_eq!(e.to_string(), "[not_initialized] call `init` first");
+
+    assert_eq!(NeorqError::from(anyhow::anyhow!("huh")).kind, ErrorKind::Internal);
+}
+
+#[test]
+fn errors_through_lua() {
+    let e: LuaError = NeorqError::new(ErrorKind::SqlError, "no such table: [x]").into();
+    // what comes back when lua hands the error from a callback to another callback
+    let e = LuaError::CallbackError {
+        traceback: String::from("stack traceback:"),
+        cause: std::sync::Arc::new(e),
+    };
+    let found = NeorqError::from_lua_error(&e).unwrap();
+    assert_eq!(found.kind, ErrorKind::SqlError);
+    assert_eq!(found.message, "no such table: [x]");
+
+    assert!(NeorqError::from_lua_error(&LuaError::runtime("not ours")).is_none());
+}
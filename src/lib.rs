@@ -1,23 +1,47 @@
 mod db;
 mod doc_parser;
+mod error;
 mod norg_date;
 mod orchestrator;
-
-use std::{collections::HashMap, convert::identity, fs::File, path::Path, sync::OnceLock};
-
-use anyhow::anyhow;
-use dateparser::DateTimeUtc;
-use db::{util::gets_checked, DatabaseConnection};
+mod query_lang;
+mod task_match;
+mod task_tree;
+mod watcher;
+
+use std::{
+    collections::HashMap,
+    convert::identity,
+    fs::File,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError, RwLock},
+};
+
+use chrono::NaiveDateTime;
+use db::{util::gets_checked, DatabaseConnection, TypedRows};
+use error::{ErrorKind, NeorqError};
 use itertools::Itertools;
 use libsql::Row;
 use log::{info, trace, warn};
+use notify_debouncer_mini::{notify::RecommendedWatcher, Debouncer};
 use mlua::prelude::*;
 use once_cell::sync::Lazy;
+use orchestrator::IndexOptions;
+use query_lang::QueryContext;
 use serde::{Deserialize, Serialize};
 use simplelog::{CombinedLogger, WriteLogger};
 use tokio::runtime::{self};
 
-static DB: OnceLock<DatabaseConnection> = OnceLock::new();
+/// The open database. `init` swaps it out, `close` takes it. Anything already running keeps its own
+/// clone of the old connection until it's done
+static DB: Lazy<RwLock<Option<DatabaseConnection>>> = Lazy::new(Default::default);
+
+/// Directories that we're watching for changes, by path. Dropping a debouncer stops its watcher
+static WATCHERS: Lazy<Mutex<HashMap<PathBuf, Debouncer<RecommendedWatcher>>>> =
+    Lazy::new(Default::default);
+
+/// Options given to `init`, reused by every later `index` call
+static INDEX_OPTIONS: Lazy<RwLock<IndexOptions>> = Lazy::new(Default::default);
 
 static TOKIO: Lazy<runtime::Runtime> = Lazy::new(|| {
     runtime::Builder::new_multi_thread()
@@ -26,59 +50,171 @@ static TOKIO: Lazy<runtime::Runtime> = Lazy::new(|| {
         .expect("cannot start tokio runtime")
 });
 
+/// The database connection, or a not_initialized error if `init` hasn't succeeded yet
+fn db() -> Result<DatabaseConnection, NeorqError> {
+    DB.read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .ok_or_else(NeorqError::not_initialized)
+}
+
+fn index_options() -> IndexOptions {
+    // the options are only ever replaced whole, so a panic while holding the lock can't leave them
+    // half written
+    INDEX_OPTIONS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Run `fut` on the tokio runtime. Errors (and panics) come back as a [NeorqError] for lua
+async fn run<T: Send + 'static>(
+    fut: impl Future<Output = anyhow::Result<T>> + Send + 'static,
+) -> LuaResult<T> {
+    match TOKIO.handle().spawn(fut).await {
+        Ok(res) => res.map_err(|e| NeorqError::from(e).into()),
+        Err(e) => Err(NeorqError::new(ErrorKind::Internal, format!("task failed: {e}")).into()),
+    }
+}
+
 /// Initialize the Database connection, optionally perform the initial workspace index
-/// Returns true on success, false on failure
+/// `opts` is an optional table of [IndexOptions]
+/// Can be called again to switch to another database/workspace, the old connection is closed once
+/// the new one is open. Returns a table with the `view_errors` of views from `opts.views` that
+/// couldn't be created, these don't fail the init. Errors with the reason on failure
 async fn init(
-    _: Lua,
-    (database_path, workspace_path, do_index): (String, String, bool),
-) -> LuaResult<bool> {
-    let handle = TOKIO.handle();
-
-    let res = handle
-        .spawn(async move {
-            let ws_path = Path::new(&workspace_path);
-            let db = DatabaseConnection::new(Path::new(&database_path))
-                .await
-                .expect("failed to create DB connection");
-
-            let _ = DB.set(db);
-            if do_index {
-                let db = DB
-                    .get()
-                    .expect("failed to get DB in init (should not be possible)");
-                orchestrator::index_workspace(ws_path, db).await
-            } else {
-                Ok(())
-            }
-        })
-        .await;
+    lua: Lua,
+    (database_path, workspace_path, do_index, opts): (String, String, bool, LuaValue),
+) -> LuaResult<LuaValue> {
+    let mut opts: IndexOptions = match opts {
+        LuaValue::Nil => IndexOptions::default(),
+        opts => lua
+            .from_value(opts)
+            .map_err(|e| NeorqError::new(ErrorKind::ParseError, format!("bad options: {e}")))?,
+    };
+    opts.workspace = Some(PathBuf::from(&workspace_path));
+
+    run(open(database_path, workspace_path, do_index, opts))
+        .await
+        .and_then(|res| lua.to_value(&res))
+}
+
+/// The part of [init] that doesn't need lua
+async fn open(
+    database_path: String,
+    workspace_path: String,
+    do_index: bool,
+    opts: IndexOptions,
+) -> anyhow::Result<InitResponse> {
+    let ws_path = Path::new(&workspace_path);
+    let mut db = DatabaseConnection::new(Path::new(&database_path)).await?;
+    let view_errors = db.create_views(&opts.views, opts.temp_views).await?;
+    db.set_full_text(opts.full_text).await?;
+
+    // if the new database can't be opened we keep using the old one
+    *INDEX_OPTIONS.write().unwrap_or_else(PoisonError::into_inner) = opts.clone();
+    *DB.write().unwrap_or_else(PoisonError::into_inner) = Some(db.clone());
+    // watchers write to the database that was open when they started
+    WATCHERS.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    register_workspace(&db, &opts).await?;
+    if do_index {
+        orchestrator::index_workspace(ws_path, &db, &opts).await?;
+    }
+    Ok(InitResponse { view_errors })
+}
+
+#[derive(Debug, Serialize)]
+struct InitResponse {
+    /// Views from the options that couldn't be created, name -> error
+    view_errors: HashMap<String, String>,
+}
+
+/// Add the workspace from `init` to the workspaces table
+async fn register_workspace(db: &DatabaseConnection, opts: &IndexOptions) -> anyhow::Result<()> {
+    let Some(root) = &opts.workspace else {
+        return Ok(());
+    };
+    let name = match &opts.workspace_name {
+        Some(name) => name.clone(),
+        None => root
+            .file_name()
+            .map_or("default".to_string(), |n| n.to_string_lossy().to_string()),
+    };
+    db.register_workspace(&name, root).await?;
+    Ok(())
+}
+
+/// Close the database connection. Everything other than `init` errors with not_initialized until
+/// `init` is called again. Returns false if there wasn't a connection to close
+fn close(_: &Lua, _: ()) -> LuaResult<bool> {
+    Ok(close_db())
+}
 
-    Ok(res.is_ok())
+fn close_db() -> bool {
+    WATCHERS.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    DB.write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+        .is_some()
 }
 
-async fn index(_: Lua, path: String) -> LuaResult<bool> {
-    let handle = TOKIO.handle();
-    let db = DB.get().expect("failed to get DB in index");
+/// Watch a directory, indexing norg files as they're created or changed, and removing them when
+/// they're deleted, no matter what changed them. Returns false if it was already being watched
+fn watch(_: &Lua, path: String) -> LuaResult<bool> {
+    let db = db()?;
+    let path = PathBuf::from(path);
+    if !path.is_dir() {
+        let msg = format!("{path:?} isn't a directory");
+        return Err(NeorqError::new(ErrorKind::IoError, msg).into());
+    }
+
+    let mut watchers = WATCHERS.lock().unwrap_or_else(PoisonError::into_inner);
+    if watchers.contains_key(&path) {
+        return Ok(false);
+    }
+    let debouncer = watcher::watch(&path, db, index_options(), TOKIO.handle().clone())
+        .map_err(NeorqError::from)?;
+    watchers.insert(path, debouncer);
+    Ok(true)
+}
+
+/// Stop watching `path`, or every directory when there's no `path`. Returns false if nothing was
+/// being watched
+fn unwatch(_: &Lua, path: Option<String>) -> LuaResult<bool> {
+    let mut watchers = WATCHERS.lock().unwrap_or_else(PoisonError::into_inner);
+    match path {
+        Some(path) => Ok(watchers.remove(Path::new(&path)).is_some()),
+        None => {
+            let any = !watchers.is_empty();
+            watchers.clear();
+            Ok(any)
+        }
+    }
+}
+
+/// Index a single file or a whole directory. Returns the number of docs that were removed from the
+/// index because they no longer exist
+async fn index(_: Lua, path: String) -> LuaResult<usize> {
+    let db = db()?;
+    let opts = index_options();
 
     let p = Path::new(&path);
     if !p.exists() {
         warn!("doesn't exist, {path:?}");
-        return Err(anyhow!("Path doesn't exist").into_lua_err());
+        return Err(NeorqError::new(ErrorKind::IoError, format!("{path} doesn't exist")).into());
     }
 
-    let res = handle
-        .spawn(async move {
-            let path = Path::new(&path);
-            if path.is_file() {
-                trace!("indexing file {path:?}");
-                orchestrator::index_file(path, db).await
-            } else {
-                orchestrator::index_workspace(path, db).await
-            }
-        })
-        .await;
-
-    Ok(res.is_ok_and(|e| e.is_ok()))
+    run(async move {
+        register_workspace(&db, &opts).await?;
+        let path = Path::new(&path);
+        if path.is_file() {
+            trace!("indexing file {path:?}");
+            orchestrator::index_file(path, &db, &opts).await.map(|_| 0)
+        } else {
+            orchestrator::index_workspace(path, &db, &opts).await
+        }
+    })
+    .await
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -86,102 +222,287 @@ struct CategoryQueryResponse {
     path: String,
     title: Option<String>,
     description: Option<String>,
-    created: Option<String>,
-    updated: Option<String>,
+    /// unix timestamps, like dates from [user_query]
+    created: Option<i64>,
+    updated: Option<i64>,
 }
 
+/// Docs that have all (or with `or`, any) of `categories`. Only docs in `workspace` if it's given
 async fn category_query(
     lua: Lua,
-    (categories, or): (Vec<String>, Option<bool>),
+    (categories, or, workspace): (Vec<String>, Option<bool>, Option<String>),
 ) -> LuaResult<Vec<LuaValue>> {
-    let handle = TOKIO.handle();
-
-    let res = handle
-        .spawn(async move {
-            if categories.is_empty() {
-                // I feel like this will be slower, even though it's easier to write. I'm annoyed that just
-                // bail! doesn't automatically type convert
-                // (|| bail!("Need at least one category"))()?;
-                return Err(anyhow!("Need at least one category").into_lua_err());
-            }
+    let res = run(async move {
+        if categories.is_empty() {
+            // I feel like this will be slower, even though it's easier to write. I'm annoyed that just
+            // bail! doesn't automatically type convert
+            // (|| bail!("Need at least one category"))()?;
+            return Err(
+                NeorqError::new(ErrorKind::ParseError, "need at least one category").into(),
+            );
+        }
+
+        let db = db()?;
+        let q = "SELECT path, title, description, CAST(strftime('%s', created) AS INTEGER),
+                 CAST(strftime('%s', updated) AS INTEGER) FROM docs d "
+            .to_string()
+            + &if or.is_some_and(identity) {
+                "JOIN categories c ON d.id = c.file_id AND (".to_string()
+                    + &(0..categories.len())
+                        .map(|i| format!("c.name = ?{}", i + 1))
+                        .join(" OR ")
+                    + ") GROUP BY d.id"
+            } else {
+                (0..categories.len())
+                    .map(|i| {
+                        format!(
+                            "JOIN categories c{0} ON d.id = c{0}.file_id AND c{0}.name = ?{0}",
+                            i + 1
+                        )
+                    })
+                    .join(" ")
+                    + " GROUP BY d.id"
+            };
+
+        let mut rows = db
+            .workspace_query(&q, categories, workspace.as_deref())
+            .await?;
+        let mut res = vec![];
+        while let Ok(Some(row)) = rows.next().await {
+            res.push(CategoryQueryResponse {
+                path: gets_checked(&row, 0).unwrap_or_default(),
+                title: gets_checked(&row, 1),
+                description: gets_checked(&row, 2),
+                created: row.get::<Option<i64>>(3).ok().flatten(),
+                updated: row.get::<Option<i64>>(4).ok().flatten(),
+            })
+        }
 
-            let db = DB.get().expect("failed to get DB in category query");
-            let q = "SELECT path, title, description, created, updated FROM docs d ".to_string()
-                + &if or.is_some_and(identity) {
-                    "JOIN categories c ON d.id = c.file_id AND (".to_string()
-                        + &(0..categories.len())
-                            .map(|i| format!("c.name = ?{}", i + 1))
-                            .join(" OR ")
-                        + ") GROUP BY d.id"
-                } else {
-                    (0..categories.len())
-                        .map(|i| {
-                            format!(
-                                "JOIN categories c{0} ON d.id = c{0}.file_id AND c{0}.name = ?{0}",
-                                i + 1
-                            )
-                        })
-                        .join(" ")
-                        + " GROUP BY d.id"
-                };
-
-            let mut rows = db.user_query(&q, categories).await?;
-            let mut res = vec![];
-            while let Ok(Some(row)) = rows.next().await {
-                res.push(CategoryQueryResponse {
-                    path: gets_checked(&row, 0).expect("file didn't have a path"),
-                    title: gets_checked(&row, 1),
-                    description: gets_checked(&row, 2),
-                    created: gets_checked(&row, 3),
-                    updated: gets_checked(&row, 4),
-                })
+        Ok(res)
+    })
+    .await?;
+
+    Ok(res
+        .iter()
+        .filter_map(|x| lua.to_value(&x).ok())
+        .collect())
+}
+
+/// Every category, or every category in `workspace`
+async fn all_categories(_lua: Lua, workspace: Option<String>) -> LuaResult<Vec<String>> {
+    run(async move {
+        let db = db()?;
+        let q = "SELECT DISTINCT name FROM categories";
+
+        let mut rows = db.workspace_query(q, (), workspace.as_deref()).await?;
+        let mut res = vec![];
+        while let Ok(Some(row)) = rows.next().await {
+            if let Some(name) = gets_checked(&row, 0) {
+                res.push(name);
             }
+        }
+        Ok::<Vec<String>, anyhow::Error>(res)
+    })
+    .await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BacklinkResponse {
+    /// doc that the link is in
+    path: String,
+    title: Option<String>,
+    /// heading that the link is under
+    heading: Option<String>,
+    line: Option<i64>,
+    kind: String,
+    /// heading (or other named target) that the link points to
+    target_heading: Option<String>,
+}
 
-            Ok(res)
-        })
-        .await;
+/// Find every link to the file at `path`, or to `heading` in that file. `path` can be absolute, or
+/// a `$/` workspace relative norg link target
+async fn backlinks(
+    lua: Lua,
+    (path, heading): (String, Option<String>),
+) -> LuaResult<Vec<LuaValue>> {
+    let workspace = index_options().workspace;
+
+    let res = run(async move {
+        let path = resolve_arg_path(path, workspace.as_deref())?;
+        let db = db()?;
+        let heading_filter = if heading.is_some() {
+            "AND lower(trim(l.target_heading)) = lower(trim(?2))"
+        } else {
+            ""
+        };
+        let q = format!(
+            "SELECT d.path, d.title, l.source_heading, l.line, l.kind, l.target_heading
+             FROM links l JOIN docs d ON d.id = l.file_id
+             WHERE l.resolved_path = ?1 {heading_filter}
+             ORDER BY d.path, l.line"
+        );
+
+        let mut params = vec![path];
+        params.extend(heading);
+        let mut rows = db.user_query(&q, params).await?;
+        let mut res = vec![];
+        while let Ok(Some(row)) = rows.next().await {
+            res.push(BacklinkResponse {
+                path: gets_checked(&row, 0).unwrap_or_default(),
+                title: gets_checked(&row, 1),
+                heading: gets_checked(&row, 2),
+                line: row.get::<Option<i64>>(3).ok().flatten(),
+                kind: gets_checked(&row, 4).unwrap_or_default(),
+                target_heading: gets_checked(&row, 5),
+            })
+        }
+
+        Ok::<Vec<BacklinkResponse>, anyhow::Error>(res)
+    })
+    .await?;
 
     Ok(res
-        .expect("cat query task failed")
-        .expect("cat query task returned Err")
         .iter()
         .filter_map(|x| lua.to_value(&x).ok())
         .collect())
 }
 
-async fn all_categories(_lua: Lua, _: ()) -> LuaResult<Vec<String>> {
-    let handle = TOKIO.handle();
+#[derive(Debug, Serialize, Deserialize)]
+struct SearchResponse {
+    path: String,
+    title: Option<String>,
+    /// headings that the match is under, joined with ` > `
+    heading: Option<String>,
+    line: Option<i64>,
+    /// matched text, with the matching terms surrounded by `*`
+    snippet: String,
+    /// bm25 rank, lower is a better match
+    rank: f64,
+}
 
-    let res = handle
-        .spawn(async move {
-            let db = DB.get().expect("failed to get DB in all_categories");
-            let q = "SELECT DISTINCT name FROM categories";
+/// Full text search over the body of every document, best matches first. `query` uses the SQLite
+/// FTS5 query syntax. Only returns results when `full_text` was enabled in `init`. Only documents
+/// in the named workspace when `workspace` is given
+async fn search(
+    lua: Lua,
+    (query, limit, workspace): (String, Option<i64>, Option<String>),
+) -> LuaResult<Vec<LuaValue>> {
+    let res = run(async move {
+        let db = db()?;
+        let mut rows = db
+            .search(&query, limit.unwrap_or(20), workspace.as_deref())
+            .await?;
+        let mut res = vec![];
+        while let Ok(Some(row)) = rows.next().await {
+            res.push(SearchResponse {
+                path: gets_checked(&row, 0).unwrap_or_default(),
+                title: gets_checked(&row, 1),
+                heading: gets_checked(&row, 2),
+                line: row.get::<Option<i64>>(3).ok().flatten(),
+                snippet: gets_checked(&row, 4).unwrap_or_default(),
+                rank: row.get::<f64>(5).unwrap_or_default(),
+            })
+        }
 
-            let mut rows = db.user_query(q, ()).await?;
-            let mut res = vec![];
-            while let Ok(Some(row)) = rows.next().await {
-                if let Some(name) = gets_checked(&row, 0) {
-                    res.push(name);
-                }
-            }
-            Ok::<Vec<String>, anyhow::Error>(res)
-        })
-        .await;
+        Ok::<Vec<SearchResponse>, anyhow::Error>(res)
+    })
+    .await?;
 
     Ok(res
-        .expect("all cat task failed")
-        .expect("all cat task returned Err"))
+        .iter()
+        .filter_map(|x| lua.to_value(&x).ok())
+        .collect())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiagnosticResponse {
+    path: String,
+    line: Option<i64>,
+    /// `due`, `starts`, `timestamp`, `recurrence`, `created`, or `updated`
+    kind: String,
+    /// the text that we couldn't parse
+    text: String,
+    message: String,
+}
+
+/// Everything we couldn't parse in the file at `path`, or in every file when there's no `path`.
+/// `path` can be absolute, or a `$/` workspace relative norg link target
+async fn diagnostics(lua: Lua, path: Option<String>) -> LuaResult<Vec<LuaValue>> {
+    let workspace = index_options().workspace;
+
+    let res = run(async move {
+        let path = path
+            .map(|p| resolve_arg_path(p, workspace.as_deref()))
+            .transpose()?;
+        let db = db()?;
+        let path_filter = if path.is_some() { "WHERE d.path = ?1" } else { "" };
+        let q = format!(
+            "SELECT d.path, g.line, g.kind, g.text, g.message
+             FROM diagnostics g JOIN docs d ON d.id = g.file_id
+             {path_filter}
+             ORDER BY d.path, g.line"
+        );
+
+        let params: Vec<String> = path.into_iter().collect();
+        let mut rows = db.user_query(&q, params).await?;
+        let mut res = vec![];
+        while let Ok(Some(row)) = rows.next().await {
+            res.push(DiagnosticResponse {
+                path: gets_checked(&row, 0).unwrap_or_default(),
+                line: row.get::<Option<i64>>(1).ok().flatten(),
+                kind: gets_checked(&row, 2).unwrap_or_default(),
+                text: gets_checked(&row, 3).unwrap_or_default(),
+                message: gets_checked(&row, 4).unwrap_or_default(),
+            })
+        }
+
+        Ok::<Vec<DiagnosticResponse>, anyhow::Error>(res)
+    })
+    .await?;
+
+    Ok(res
+        .iter()
+        .filter_map(|x| lua.to_value(&x).ok())
+        .collect())
+}
+
+/// Paths passed in from lua can be absolute, `$/` and `~/` norg link targets, or relative to the
+/// workspace. The `.norg` is optional for everything but absolute paths
+fn resolve_arg_path(path: String, workspace: Option<&Path>) -> anyhow::Result<String> {
+    let target = if path.starts_with('$') || path.starts_with('~') {
+        path.clone()
+    } else if Path::new(&path).is_relative() {
+        format!("$/{path}")
+    } else {
+        return Ok(path);
+    };
+    let resolved = doc_parser::resolve_norg_path(&target, Path::new("/"), workspace)
+        .ok_or_else(|| NeorqError::new(ErrorKind::ParseError, format!("couldn't resolve {path}")))?;
+    Ok(resolved.to_string_lossy().to_string())
 }
 
 // I hate that I have to do this. libsql::de doesn't deserialize to "any", only to specific
 // structs.
 // Also, when you try to construct a LuaValue yourself, passing it back to rust results in a list
 // of userdata values
-fn row2value(row: &Row) -> anyhow::Result<HashMap<String, serde_json::Value>> {
+//
+// Columns declared as dates (`DATETIME`, `DATE`, `TIMESTAMP`) come back as unix timestamps, no
+// matter what they're called in the query
+fn row2value(
+    row: &Row,
+    decl_types: &[Option<String>],
+) -> anyhow::Result<HashMap<String, serde_json::Value>> {
     let mut table: HashMap<String, serde_json::Value> = HashMap::new();
     trace!("{row:?}");
     for i in 0..row.column_count() {
         let name = row.column_name(i);
+        let is_date = decl_types
+            .get(i as usize)
+            .and_then(|t| t.as_deref())
+            .is_some_and(|t| {
+                let t = t.to_uppercase();
+                t.contains("DATE") || t.contains("TIME")
+            });
         let t = row.column_type(i)?;
         let value = match t {
             libsql::ValueType::Integer => Some(serde_json::to_value(
@@ -192,11 +513,9 @@ fn row2value(row: &Row) -> anyhow::Result<HashMap<String, serde_json::Value>> {
             }
             libsql::ValueType::Text => {
                 Some(row.get_value(i)?.as_text().map(|s| {
-                    if let Some(name) = name {
-                        if ["start", "due", "timestamp"].contains(&name) {
-                            if let Ok(d) = s.parse::<DateTimeUtc>() {
-                                return serde_json::to_value(d.0.timestamp())
-                            }
+                    if is_date {
+                        if let Ok(d) = NaiveDateTime::parse_from_str(s, norg_date::SQL_FORMAT) {
+                            return serde_json::to_value(d.and_utc().timestamp());
                         }
                     }
                     serde_json::to_value(s)
@@ -216,26 +535,134 @@ fn row2value(row: &Row) -> anyhow::Result<HashMap<String, serde_json::Value>> {
     Ok(table)
 }
 
-async fn user_query(lua: Lua, (query, params): (String, Vec<String>)) -> LuaResult<LuaValue> {
-    let handle = TOKIO.handle();
-    let res = handle
-        .spawn(async move {
-            let db = DB.get().expect("fail to get DB in user_query");
-            let mut rows = db.user_query(&query, params).await?;
-            let mut values = vec![];
-            while let Ok(Some(row)) = rows.next().await {
-                values.push(row2value(&row)?);
-            }
-            Ok::<Vec<HashMap<String, serde_json::Value>>, anyhow::Error>(values)
-        })
-        .await;
+async fn collect_rows(
+    TypedRows { mut rows, decl_types }: TypedRows,
+) -> anyhow::Result<Vec<HashMap<String, serde_json::Value>>> {
+    let mut values = vec![];
+    while let Ok(Some(row)) = rows.next().await {
+        values.push(row2value(&row, &decl_types)?);
+    }
+    Ok(values)
+}
 
-    let res = res.expect("user_query task failed")?;
+/// Run a read only query. With `workspace`, the query only sees that workspace's docs
+async fn user_query(
+    lua: Lua,
+    (query, params, workspace): (String, Vec<String>, Option<String>),
+) -> LuaResult<LuaValue> {
+    let res = run(async move {
+        let db = db()?;
+        let rows = db
+            .typed_query(&query, params, workspace.as_deref())
+            .await?;
+        collect_rows(rows).await
+    })
+    .await?;
     let tab = lua.to_value(&res)?;
 
     Ok(tab)
 }
 
+/// Run a query written in our query language (see [query_lang]), results are the same shape as
+/// [user_query]'s. Syntax errors are parse_errors that start with `line:col:`
+async fn query(lua: Lua, (source, workspace): (String, Option<String>)) -> LuaResult<LuaValue> {
+    let opts = index_options();
+    let zone = &opts.dates.timezone;
+    let ctx = QueryContext {
+        workspace: opts.workspace.as_deref(),
+        zone,
+        now: zone.now(),
+    };
+    let compiled = query_lang::compile(&source, &ctx)
+        .map_err(|e| NeorqError::new(ErrorKind::ParseError, e.to_string()))?;
+
+    let res = run(async move {
+        let db = db()?;
+        let rows = db
+            .typed_query(&compiled.sql, compiled.params, workspace.as_deref())
+            .await?;
+        collect_rows(rows).await
+    })
+    .await?;
+
+    Ok(lua.to_value(&res)?)
+}
+
+/// Tasks that match `query`, nested under their parents as `children`. Ancestors of tasks that
+/// matched are included too, so every match shows up where it lives; each task has a `matched`
+/// field to tell them apart. `query` is either a `TASKS` query in our query language, or SQL that
+/// returns a `task_id` column
+async fn task_tree_query(
+    lua: Lua,
+    (query, params, workspace): (String, Option<Vec<String>>, Option<String>),
+) -> LuaResult<LuaValue> {
+    let is_lang = query
+        .trim_start()
+        .get(..5)
+        .is_some_and(|w| w.eq_ignore_ascii_case("tasks"));
+    let (sql, params) = if is_lang {
+        let opts = index_options();
+        let zone = &opts.dates.timezone;
+        let ctx = QueryContext {
+            workspace: opts.workspace.as_deref(),
+            zone,
+            now: zone.now(),
+        };
+        let compiled = query_lang::compile(&query, &ctx)
+            .map_err(|e| NeorqError::new(ErrorKind::ParseError, e.to_string()))?;
+        (compiled.sql, compiled.params)
+    } else {
+        let params = params.unwrap_or_default();
+        (query, params.into_iter().map(libsql::Value::Text).collect())
+    };
+
+    let res = run(async move {
+        let db = db()?;
+        let ws = workspace.as_deref();
+        let rows = collect_rows(db.typed_query(&sql, params, ws).await?).await?;
+        let matched: Vec<i64> = rows
+            .iter()
+            .filter_map(|row| row.get("task_id").and_then(serde_json::Value::as_i64))
+            .collect();
+        if matched.is_empty() && !rows.is_empty() {
+            let msg = "the query has to return a `task_id` column";
+            return Err(NeorqError::new(ErrorKind::ParseError, msg).into());
+        }
+
+        // the matches, and everything above them
+        let q = "WITH RECURSIVE tree(id) AS (
+                    SELECT value FROM json_each(?1)
+                    UNION
+                    SELECT t.parent_id FROM tasks t JOIN tree ON t.task_id = tree.id
+                    WHERE t.parent_id IS NOT NULL
+                 )
+                 SELECT t.*, d.path, d.title FROM tasks t JOIN docs d ON d.id = t.file_id
+                 WHERE t.task_id IN (SELECT id FROM tree)";
+        let ids = serde_json::to_string(&matched)?;
+        let tasks = collect_rows(db.typed_query(q, [ids], ws).await?).await?;
+        Ok(task_tree::build(tasks, &matched))
+    })
+    .await?;
+
+    Ok(lua.to_value(&res)?)
+}
+
+/// The kind and message of an error thrown by one of our functions, anything else is `internal`
+fn error_info(_: &Lua, err: LuaValue) -> LuaResult<NeorqError> {
+    Ok(match &err {
+        LuaValue::Error(e) => NeorqError::from_lua_error(e)
+            .cloned()
+            .unwrap_or_else(|| {
+                // mlua tacks a traceback on to errors from callbacks, no one needs that in a
+                // notification
+                let msg = e.to_string();
+                let msg = msg.split("\nstack traceback").next().unwrap_or_default();
+                NeorqError::new(ErrorKind::Internal, msg)
+            }),
+        v => NeorqError::new(ErrorKind::Internal, v.to_string()?),
+    })
+}
+
 // async fn greet(_lua: Lua, name: String) -> LuaResult<String> {
 //     let _guard = TOKIO.enter();
 //
@@ -264,10 +691,19 @@ fn libneorg_query(lua: &Lua) -> LuaResult<LuaTable> {
 
     let exports = lua.create_table()?;
     exports.set("init", lua.create_async_function(init)?)?;
+    exports.set("close", lua.create_function(close)?)?;
     exports.set("index", lua.create_async_function(index)?)?;
+    exports.set("watch", lua.create_function(watch)?)?;
+    exports.set("unwatch", lua.create_function(unwatch)?)?;
     exports.set("category_query", lua.create_async_function(category_query)?)?;
     exports.set("all_categories", lua.create_async_function(all_categories)?)?;
+    exports.set("backlinks", lua.create_async_function(backlinks)?)?;
+    exports.set("search", lua.create_async_function(search)?)?;
+    exports.set("diagnostics", lua.create_async_function(diagnostics)?)?;
     exports.set("user_query", lua.create_async_function(user_query)?)?;
+    exports.set("query", lua.create_async_function(query)?)?;
+    exports.set("task_tree_query", lua.create_async_function(task_tree_query)?)?;
+    exports.set("error_info", lua.create_function(error_info)?)?;
 
     exports.set(
         "PENDING",
@@ -278,3 +714,53 @@ fn libneorg_query(lua: &Lua) -> LuaResult<LuaTable> {
     )?;
     Ok(exports)
 }
+
+#[test]
+fn resolve_arg_paths() {
+    let ws = Some(Path::new("/notes"));
+    let resolve = |p: &str| resolve_arg_path(p.to_string(), ws).unwrap();
+
+    assert_eq!(resolve("$/journal/today"), "/notes/journal/today.norg");
+    // the extension isn't added twice
+    assert_eq!(resolve("$/journal/today.norg"), "/notes/journal/today.norg");
+    assert_eq!(resolve("journal/today"), "/notes/journal/today.norg");
+    assert_eq!(resolve("./journal/../index.norg"), "/notes/index.norg");
+    assert_eq!(resolve("/elsewhere/file.norg"), "/elsewhere/file.norg");
+    assert!(resolve_arg_path("journal".to_string(), None).is_err());
+}
+
+#[tokio::test]
+async fn reopen_another_workspace() {
+    let dir = db::util::test_dir("reopen");
+    let paths = |name: &str| {
+        let ws = dir.join(name);
+        std::fs::create_dir_all(&ws).unwrap();
+        std::fs::write(ws.join(format!("{name}.norg")), "* heading\n").unwrap();
+        let db = dir.join(format!("{name}.sqlite")).to_string_lossy().to_string();
+        (db, ws.to_string_lossy().to_string())
+    };
+    let opts = |ws: &str| IndexOptions {
+        workspace: Some(PathBuf::from(ws)),
+        ..IndexOptions::default()
+    };
+    let docs = || async {
+        let mut rows = db().unwrap().user_query("SELECT path FROM docs", ()).await.unwrap();
+        let mut found: Vec<String> = vec![];
+        while let Some(row) = rows.next().await.unwrap() {
+            found.push(row.get(0).unwrap());
+        }
+        found
+    };
+
+    let (db_a, ws_a) = paths("a");
+    open(db_a, ws_a.clone(), true, opts(&ws_a)).await.unwrap();
+    assert_eq!(docs().await, [format!("{ws_a}/a.norg")]);
+
+    assert!(close_db());
+    assert_eq!(db().err().map(|e| e.kind), Some(ErrorKind::NotInitialized));
+    assert!(!close_db());
+
+    let (db_b, ws_b) = paths("b");
+    open(db_b, ws_b.clone(), true, opts(&ws_b)).await.unwrap();
+    assert_eq!(docs().await, [format!("{ws_b}/b.norg")]);
+}
@@ -3,8 +3,157 @@
 use anyhow::anyhow;
 use anyhow::{bail, Result};
 use chrono::prelude::*;
+use chrono::LocalResult;
+use chrono_tz::Tz;
 use log::info;
-use regex::Regex;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+/// Options that change how dates are read
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DateOptions {
+    /// Zone for dates that don't name one
+    pub timezone: Zone,
+    #[serde(rename = "date_strictness")]
+    pub strictness: Strictness,
+}
+
+/// Which date formats we accept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strictness {
+    /// only what the norg spec describes
+    #[default]
+    Strict,
+    /// also American order, ISO 8601, and relative dates like `tomorrow`
+    Lenient,
+}
+
+/// The zone that a date's wall clock time is in
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Zone {
+    /// whatever the system's zone is
+    #[default]
+    Local,
+    /// abbreviations like `EST` always mean the same offset, DST or not
+    Fixed(FixedOffset),
+    /// IANA names like `America/New_York`
+    Named(Tz),
+}
+
+/// Abbreviations we understand, and their offsets from UTC in minutes. They're ambiguous (`IST` is
+/// India, Ireland, and Israel), so this picks the most common meaning, anything else can be written
+/// as an IANA name
+const ABBREVIATIONS: &[(&str, i32)] = &[
+    ("UTC", 0),
+    ("GMT", 0),
+    ("Z", 0),
+    ("WET", 0),
+    ("WEST", 60),
+    ("BST", 60),
+    ("CET", 60),
+    ("CEST", 120),
+    ("EET", 120),
+    ("EEST", 180),
+    ("MSK", 180),
+    ("IST", 330),
+    ("CST", -360),
+    ("CDT", -300),
+    ("EST", -300),
+    ("EDT", -240),
+    ("MST", -420),
+    ("MDT", -360),
+    ("PST", -480),
+    ("PDT", -420),
+    ("AKST", -540),
+    ("AKDT", -480),
+    ("HST", -600),
+    ("JST", 540),
+    ("KST", 540),
+    ("AWST", 480),
+    ("ACST", 570),
+    ("AEST", 600),
+    ("AEDT", 660),
+    ("NZST", 720),
+    ("NZDT", 780),
+];
+
+impl Zone {
+    pub fn parse(s: &str) -> Result<Zone> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(Zone::Local);
+        }
+        if let Some((_, minutes)) = ABBREVIATIONS.iter().find(|(a, _)| a.eq_ignore_ascii_case(s)) {
+            let offset = FixedOffset::east_opt(minutes * 60).ok_or(anyhow!("Bad offset"))?;
+            return Ok(Zone::Fixed(offset));
+        }
+        // numeric offsets, `+05:30` or `-0500`
+        let sign = match s.chars().next() {
+            Some('+') => Some(1),
+            Some('-') => Some(-1),
+            _ => None,
+        };
+        if let Some(sign) = sign {
+            let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+            if digits.len() == 4 && digits.chars().all(|c| c.is_ascii_digit()) {
+                let (h, m): (i32, i32) = (digits[..2].parse()?, digits[2..].parse()?);
+                let offset = FixedOffset::east_opt(sign * (h * 3600 + m * 60))
+                    .ok_or(anyhow!("Bad offset"))?;
+                return Ok(Zone::Fixed(offset));
+            }
+        }
+        s.parse::<Tz>()
+            .map(Zone::Named)
+            .map_err(|_| anyhow!("Unknown time zone {s}"))
+    }
+
+    /// Current wall clock time in this zone
+    pub fn now(&self) -> NaiveDateTime {
+        match self {
+            Zone::Local => Local::now().naive_local(),
+            Zone::Fixed(offset) => Utc::now().with_timezone(offset).naive_local(),
+            Zone::Named(tz) => Utc::now().with_timezone(tz).naive_local(),
+        }
+    }
+
+    /// Convert a wall clock time in this zone to UTC. Around DST changes, times that happen twice
+    /// use the earlier one, and times that are skipped use the offset from before the gap, so
+    /// 02:30 on the night the clocks go from 02:00 to 03:00 comes out as 03:30
+    pub fn to_utc(&self, date: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::Local => to_utc(&Local, date),
+            Zone::Fixed(offset) => to_utc(offset, date),
+            Zone::Named(tz) => to_utc(tz, date),
+        }
+    }
+}
+
+fn to_utc<T: TimeZone>(tz: &T, date: NaiveDateTime) -> Option<DateTime<Utc>> {
+    match tz.from_local_datetime(&date) {
+        LocalResult::Single(d) => Some(d.with_timezone(&Utc)),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest.with_timezone(&Utc)),
+        LocalResult::None => {
+            // in a gap. Gaps are an hour almost everywhere, but some are half an hour or two, so
+            // step back until we're out of it and use the offset from there
+            let before = (1..=24).find_map(|hours| {
+                tz.from_local_datetime(&(date - chrono::Duration::hours(hours)))
+                    .earliest()
+            })?;
+            let offset = before.offset().fix().local_minus_utc();
+            let utc = date.checked_sub_signed(chrono::Duration::seconds(offset.into()))?;
+            Some(utc.and_utc())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Zone {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Zone, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Zone::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
 
 fn weekday_string(w: Weekday) -> String {
     match w {
@@ -19,132 +168,687 @@ fn weekday_string(w: Weekday) -> String {
     .to_string()
 }
 
-// Parse a norg date string (as described in the spec) into a chrono DateTime UTC
-pub fn parse(s: &str) -> Result<DateTime<Utc>> {
-    let months = [
-        "january",
-        "february",
-        "march",
-        "april",
-        "may",
-        "june",
-        "july",
-        "august",
-        "september",
-        "october",
-        "november",
-        "december",
-    ];
+/// How every date is stored in the database: UTC, in the same format as sqlite's
+/// `CURRENT_TIMESTAMP` and `datetime()`. Comparing and sorting these as text works
+pub const SQL_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
+/// Format a date the way it's stored, see [SQL_FORMAT]
+pub fn to_sql(date: DateTime<Utc>) -> String {
+    date.format(SQL_FORMAT).to_string()
+}
+
+// Parse a norg date string (as described in the spec) into a chrono DateTime UTC. Dates without a
+// time zone are in the default zone from `opts`. Missing parts are filled in relative to `today`:
+// - no year: the next time that date comes around (that also falls on the weekday, if there is one)
+// - no month: the next month with that day in it
+// - only a weekday: the next one
+// - no time: midnight
+// "next" includes today
+//
+// In lenient mode, dates that don't follow the spec are also accepted, see `parse_lenient`
+pub fn parse(s: &str, today: NaiveDate, opts: &DateOptions) -> Result<DateTime<Utc>> {
+    match parse_strict(s, today, opts) {
+        Err(e) if opts.strictness == Strictness::Lenient => {
+            parse_lenient(s.trim(), today, opts).unwrap_or(Err(e))
+        }
+        res => res,
+    }
+}
+
+// time and zone, the same for every format
+const TIME: &str = r"(?<hour>\d{1,2}):(?<min>\d{2})(?:[.:](?<seconds>\d{1,2}))?";
+const TZ: &str = r"(?<tz>[A-Z]{1,5}|[A-Za-z_]+(?:/[A-Za-z0-9_+-]+)+|[+-]\d{2}:?\d{2})";
+// month names and the usual ways they're shortened, for the lenient formats
+const MONTH: &str = concat!(
+    r"(?:jan(?:uary)?|feb(?:ruary)?|mar(?:ch)?|apr(?:il)?|may|june?|july?|aug(?:ust)?",
+    r"|sep(?:t(?:ember)?)?|oct(?:ober)?|nov(?:ember)?|dec(?:ember)?)"
+);
+
+fn parse_strict(s: &str, today: NaiveDate, opts: &DateOptions) -> Result<DateTime<Utc>> {
     // `<day>?,? <day-of-month> <month> -?<year> <time> <timezone>`
+    static RE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(&format!(
+            r"(?x)
+            ^(?<day>[[:alpha:]]+\>)?,?\s?
+            (?<dom>\<\d{{1,2}})?(?:(?:(?:th|nd|st|rd)\>)?|\>)\s?
+            (?<month>\<[[:alpha:]]+\>)?\s?
+            -?(?<year>\<\d{{4,}}\>)?\s?
+            (?<full_time>\<{TIME}\>)?\s?
+            {TZ}?$"
+        ))
+        .unwrap()
+    });
+
+    let Some(m) = RE.captures(s) else {
+        bail!("date string doesn't match date regex")
+    };
+
+    let (mut day, mut month) = (m.name("day"), m.name("month"));
+    // `Jan 2026` reads as a weekday followed by a year, it's really a month. `Jan 1 2025` isn't in
+    // the spec though, that's left to lenient mode
+    if let (Some(d), None, None) = (day, month, m.name("dom")) {
+        if weekday_from_str(d.as_str()).is_none() {
+            (day, month) = (None, Some(d));
+        }
+    }
+    if day.is_none() && m.name("dom").is_none() && month.is_none() && m.name("year").is_none() {
+        if m.name("full_time").is_none() {
+            bail!("Empty date");
+        }
+        // just a time, that's today
+        return finish(today, &m, opts);
+    }
+
+    let date = resolve_date(
+        DateParts {
+            weekday: day.map(|d| d.as_str()),
+            dom: m.name("dom").map(|d| d.as_str()),
+            month: month.map(|m| m.as_str()),
+            year: m.name("year").map(|y| y.as_str()),
+        },
+        today,
+    )?;
+    finish(date, &m, opts)
+}
+
+// Formats that aren't in the spec, but that people write anyway:
+// - `Jan 1 2025`, `Wed, January 1st, 2025`, `Jan 1` (American order)
+// - `2025-01-01`, `2025-01-01T10:00`, `2025-01-01 10:00:30Z` (ISO 8601)
+// - `today`, `tomorrow`, `yesterday`, `next friday`, `in 3 days`, `in 2 weeks`
+// each of them can be followed by a time and zone. None if `s` isn't in any of these formats
+fn parse_lenient(s: &str, today: NaiveDate, opts: &DateOptions) -> Option<Result<DateTime<Utc>>> {
+    static AMERICAN: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(&format!(
+            r"(?xi)
+            ^(?:(?<day>[[:alpha:]]+),?\s+)?
+            (?<month>{MONTH})\.?\s+
+            (?<dom>\d{{1,2}})(?:th|nd|st|rd)?,?
+            (?:\s+(?<year>\d{{4,}}))?
+            (?:,?\s+(?:at\s+)?{TIME})?
+            (?:\s*(?-i:{TZ}))?$"
+        ))
+        .unwrap()
+    });
+    static ISO: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(&format!(
+            r"(?x)
+            ^(?<year>\d{{4}})-(?<month>\d{{2}})-(?<dom>\d{{2}})
+            (?:[T\s]{TIME})?
+            (?:\s*{TZ})?$"
+        ))
+        .unwrap()
+    });
+    static RELATIVE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(&format!(
+            r"(?xi)
+            ^(?:(?<today>today)|(?<tomorrow>tomorrow)|(?<yesterday>yesterday)
+              |next\s+(?<next>[[:alpha:]]+)
+              |in\s+(?<count>\d+)\s+(?<unit>days?|weeks?))
+            (?:,?\s+(?:at\s+)?{TIME})?
+            (?:\s*(?-i:{TZ}))?$"
+        ))
+        .unwrap()
+    });
+
+    // before the others, `in 3 days` looks a lot like a month, a day and a time zone
+    if let Some(m) = RELATIVE.captures(s) {
+        let date = if m.name("today").is_some() {
+            Some(today)
+        } else if m.name("tomorrow").is_some() {
+            today.succ_opt()
+        } else if m.name("yesterday").is_some() {
+            today.pred_opt()
+        } else if let Some(next) = m.name("next") {
+            // `next friday` on a friday is a week away, not today
+            match weekday_from_str(next.as_str()) {
+                Some(w) => today.iter_days().skip(1).find(|d| d.weekday() == w),
+                None => return Some(Err(anyhow!("Unknown weekday"))),
+            }
+        } else {
+            let Ok(count) = m["count"].parse::<u64>() else {
+                return Some(Err(anyhow!("Date out of range")));
+            };
+            let days = match m["unit"].to_lowercase().starts_with("week") {
+                true => count.checked_mul(7),
+                false => Some(count),
+            };
+            days.and_then(|days| today.checked_add_days(chrono::Days::new(days)))
+        };
+        return Some(match date {
+            Some(date) => finish(date, &m, opts),
+            None => Err(anyhow!("Date out of range")),
+        });
+    }
+
+    if let Some(m) = AMERICAN.captures(s) {
+        let date = resolve_date(
+            DateParts {
+                weekday: m.name("day").map(|d| d.as_str()),
+                dom: m.name("dom").map(|d| d.as_str()),
+                month: m.name("month").map(|m| m.as_str()),
+                year: m.name("year").map(|y| y.as_str()),
+            },
+            today,
+        );
+        return Some(date.and_then(|date| finish(date, &m, opts)));
+    }
+
+    if let Some(m) = ISO.captures(s) {
+        let date = NaiveDate::from_ymd_opt(
+            m["year"].parse().ok()?,
+            m["month"].parse().ok()?,
+            m["dom"].parse().ok()?,
+        );
+        return Some(match date {
+            Some(date) => finish(date, &m, opts),
+            None => Err(anyhow!("Incompatible Day")),
+        });
+    }
+
+    None
+}
+
+/// The parts of a date, as they were written
+struct DateParts<'a> {
+    weekday: Option<&'a str>,
+    dom: Option<&'a str>,
+    month: Option<&'a str>,
+    year: Option<&'a str>,
+}
+
+/// Fill in whatever's missing from `parts`, see [parse]
+fn resolve_date(parts: DateParts, today: NaiveDate) -> Result<NaiveDate> {
+    let weekday = match parts.weekday {
+        Some(day) => Some(weekday_from_str(day).ok_or(anyhow!("Unknown weekday"))?),
+        None => None,
+    };
+    let year = parts.year.map(|y| y.parse::<i32>()).transpose()?;
+    let month = match parts.month {
+        // NOTE: we're not checking for ambiguous month names, we just use the first month that
+        // matches. Probably should fail here if it's ambiguous
+        Some(month) => Some(month_from_str(month).ok_or(anyhow!("Incompatible Month"))?),
+        None => None,
+    };
+    let dom = parts.dom.map(|d| d.parse::<u32>()).transpose()?;
+
+    let on_weekday = |d: &NaiveDate| match weekday {
+        Some(w) => d.weekday() == w,
+        None => true,
+    };
+    // how far ahead we look for a date that fits, enough to get through every weekday and leap
+    // year combination
+    const YEARS: i32 = 400;
+
+    let date = match (year, month, dom) {
+        (Some(year), Some(month), dom) => {
+            if dom.is_none() && weekday.is_some() {
+                bail!("Can't infer day of month from weekday");
+            }
+            let date = NaiveDate::from_ymd_opt(year, month, dom.unwrap_or(1))
+                .ok_or(anyhow!("Incompatible Day"))?;
+            if !on_weekday(&date) {
+                bail!("Weekday doesn't match day of month");
+            }
+            date
+        }
+        (Some(_), None, _) => bail!("Can't infer month from year"),
+        (None, Some(month), dom) => {
+            if dom.is_none() && weekday.is_some() {
+                bail!("Can't infer day of month from weekday");
+            }
+            let dom = dom.unwrap_or(1);
+            (today.year()..today.year() + YEARS)
+                .filter_map(|year| NaiveDate::from_ymd_opt(year, month, dom))
+                .find(|d| *d >= today && on_weekday(d))
+                .ok_or(anyhow!("No year has that date"))?
+        }
+        (None, None, Some(dom)) => {
+            let first = today.with_day(1).expect("every month has a first");
+            (0..YEARS as u32 * 12)
+                .filter_map(|i| first.checked_add_months(chrono::Months::new(i))?.with_day(dom))
+                .find(|d| *d >= today && on_weekday(d))
+                .ok_or(anyhow!("No month has that day"))?
+        }
+        (None, None, None) => today
+            .iter_days()
+            .take(7)
+            .find(|d| on_weekday(d))
+            .expect("a week has every weekday"),
+    };
+    Ok(date)
+}
+
+/// Add the time (midnight if there isn't one) and zone from the captures to `date`, and convert it
+/// to UTC
+fn finish(date: NaiveDate, m: &Captures, opts: &DateOptions) -> Result<DateTime<Utc>> {
+    let zone = match m.name("tz") {
+        Some(tz) => Zone::parse(tz.as_str())?,
+        None => opts.timezone,
+    };
+
+    let time = match (m.name("hour"), m.name("min")) {
+        (Some(hour), Some(min)) => {
+            let sec = m.name("seconds").map_or(Ok(0), |s| s.as_str().parse())?;
+            NaiveTime::from_hms_opt(hour.as_str().parse()?, min.as_str().parse()?, sec)
+                .ok_or(anyhow!("Incompatible Time"))?
+        }
+        _ => NaiveTime::MIN,
+    };
+
+    let date = date.and_time(time);
+    info!("Parsed {date:?} in {zone:?}");
+    zone.to_utc(date).ok_or(anyhow!("Date doesn't exist in {zone:?}"))
+}
+
+/// How often a recurring task comes back around
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frequency {
+    /// on each of these days of the week
+    Weekly(Vec<Weekday>),
+    /// on each of these days of every month
+    Monthly(Vec<u32>),
+    /// on these days of this month (1-indexed) every year
+    Yearly(u32, Vec<u32>),
+}
+
+/// A recurring task's schedule, parsed from the payload of the recurring status: `(+ Mon)`,
+/// `(+ Mon-Fri)`, `(+ 5th)`, `(+ 1st-7th)`, `(+ 5th Jan)`, `(+ 24th-26th Dec)`, all optionally
+/// followed by a time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recurrence {
+    pub freq: Frequency,
+    pub time: NaiveTime,
+}
+
+fn weekday_from_str(s: &str) -> Option<Weekday> {
+    let s = s.to_lowercase();
+    if s.len() < 2 {
+        return None;
+    }
+    [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ]
+    .into_iter()
+    .find(|w| weekday_string(*w).starts_with(&s))
+}
+
+fn month_from_str(s: &str) -> Option<u32> {
+    let s = s.to_lowercase();
+    (1..=12).find(|&m| {
+        NaiveDate::from_ymd_opt(2000, m, 1)
+            .is_some_and(|d| d.format("%B").to_string().to_lowercase().starts_with(&s))
+    })
+}
+
+const RRULE_DAYS: [&str; 7] = ["MO", "TU", "WE", "TH", "FR", "SA", "SU"];
+
+// Parse the payload of a recurring status into a recurrence. A date with a year doesn't repeat, so
+// that's an error
+pub fn parse_recurrence(s: &str) -> Result<Recurrence> {
     let re = Regex::new(
         r"(?x)
-        ^(?<day>[[:alpha:]]+\>)?,?\s?
-        (?<dom>\<\d{1,2})?(?:(?:(?:th|nd|st)\>)?|\>)\s?
-        (?<month>\<[[:alpha:]]+\>)?\s?
-        -?(?<year>\<\d{4,}\>)?\s?
-        (?<full_time>(?<hour>\<\d{1,2}):(?<min>\d{2})(?:\.(?<seconds>\d{1,2}))?\>)?\s?
-        (?<tz>[A-Z]{3,4})?$",
+        ^(?:(?<day>[[:alpha:]]+)(?:\s*-\s*(?<day_end>[[:alpha:]]+))?)?,?\s*
+        (?:(?<dom>\d{1,2})(?:th|nd|st|rd)?(?:\s*-\s*(?<dom_end>\d{1,2})(?:th|nd|st|rd)?)?)?\s*
+        (?<month>[[:alpha:]]+)?\s*
+        (?<year>\d{4,})?\s*
+        (?:(?<hour>\d{1,2}):(?<min>\d{2}))?$",
     )
     .unwrap();
 
-    // Implementation Details:
-    // - Skipping recurrences for now
-    // - Since we're skipping recurrences, we can auto fill things with default values (except the
-    // day/dom b/c those two depend on each other)
-
-    if let Some(m) = re.captures(s) {
-        let date = Local::now();
-        let mut date = date.naive_utc();
-        info!("current date in UTC: {date:?}");
-        // TODO: respect time zones. Currently we just treat everything like it's local time and
-        // store in UTC.
-        // if let Some(tz) = m.name("tz") {
-        // };
-
-        if let Some(year) = m.name("year") {
-            let year = year.as_str().parse::<i32>()?;
-            date = date.with_year(year).ok_or(anyhow!("Incompatible Year"))?;
-        }
-        if let Some(month) = m.name("month") {
-            let month = month.as_str();
-            for (i, m) in months.iter().enumerate() {
-                // NOTE: we're not checking for ambiguous month names, we just use the first month
-                // that matches. Probably should fail here if it's ambiguous
-                if m.starts_with(&month.to_lowercase()) {
-                    date = date
-                        .with_month0(i.try_into().unwrap())
-                        .ok_or(anyhow!("Incompatible Month"))?;
+    let m = re
+        .captures(s.trim())
+        .ok_or(anyhow!("recurrence doesn't match recurrence regex"))?;
+    if m.name("year").is_some() {
+        bail!("A date with a year doesn't recur");
+    }
+
+    let time = match (m.name("hour"), m.name("min")) {
+        (Some(hour), Some(min)) => {
+            NaiveTime::from_hms_opt(hour.as_str().parse()?, min.as_str().parse()?, 0)
+                .ok_or(anyhow!("Incompatible Time"))?
+        }
+        _ => NaiveTime::MIN,
+    };
+
+    let weekday = |name: &str| weekday_from_str(name).ok_or(anyhow!("Unknown weekday {name}"));
+    // day of month ranges don't wrap, `25th-5th` is an error
+    let doms = || -> Result<Vec<u32>> {
+        let first: u32 = m.name("dom").unwrap().as_str().parse()?;
+        let last: u32 = match m.name("dom_end") {
+            Some(end) => end.as_str().parse()?,
+            None => first,
+        };
+        if first > last {
+            bail!("Day range {first}-{last} goes backwards");
+        }
+        Ok((first..=last).collect())
+    };
+    let freq = match (m.name("day"), m.name("dom"), m.name("month")) {
+        (Some(day), None, None) => {
+            let first = weekday(day.as_str())?;
+            let days = match m.name("day_end") {
+                // ranges wrap around the end of the week, so `Sat-Mon` is the weekend + Monday
+                Some(end) => {
+                    let end = weekday(end.as_str())?;
+                    let mut days = vec![first];
+                    while *days.last().unwrap() != end {
+                        days.push(days.last().unwrap().succ());
+                    }
+                    days
                 }
+                None => vec![first],
+            };
+            Frequency::Weekly(days)
+        }
+        (None, Some(_), None) => {
+            let doms = doms()?;
+            if doms.iter().any(|d| !(1..=31).contains(d)) {
+                bail!("Incompatible Day");
+            }
+            Frequency::Monthly(doms)
+        }
+        (_, Some(_), Some(month)) => {
+            let month = month_from_str(month.as_str()).ok_or(anyhow!("Unknown month"))?;
+            let doms = doms()?;
+            // 2000 is a leap year, so the 29th of Feb is allowed
+            if doms.iter().any(|&d| NaiveDate::from_ymd_opt(2000, month, d).is_none()) {
+                bail!("Incompatible Day");
             }
+            Frequency::Yearly(month, doms)
         }
-        if let (Some(hour), Some(min), Some(sec)) = (m.name("hour"), m.name("min"), m.name("sec")) {
-            let hour = hour.as_str().parse::<i32>()?;
-            date = date
-                .with_hour(hour.try_into().unwrap())
-                .ok_or(anyhow!("Incompatible Hour"))?;
+        _ => bail!("Can't tell how often `{s}` recurs"),
+    };
 
-            let min = min.as_str().parse::<i32>().unwrap();
-            date = date
-                .with_minute(min.try_into().unwrap())
-                .ok_or(anyhow!("Incompatible Minute"))?;
+    Ok(Recurrence { freq, time })
+}
 
-            let sec = sec.as_str().parse::<i32>().unwrap();
-            date = date
-                .with_second(sec.try_into().unwrap())
-                .ok_or(anyhow!("Incompatible Second"))?;
-        }
+impl Recurrence {
+    /// The first occurrence at or after `after`
+    pub fn next(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let on = |date: NaiveDate| date.and_time(self.time);
+        // enough days to get past any run of months or years without the date (29th of Feb)
+        (0..366 * 8)
+            .filter_map(|i| after.date().checked_add_days(chrono::Days::new(i)))
+            .filter(|date| match &self.freq {
+                Frequency::Weekly(days) => days.contains(&date.weekday()),
+                Frequency::Monthly(doms) => doms.contains(&date.day()),
+                Frequency::Yearly(month, doms) => {
+                    date.month() == *month && doms.contains(&date.day())
+                }
+            })
+            .map(on)
+            .find(|d| *d >= after)
+    }
 
-        let days = (m.name("day"), m.name("dom"));
-        let dom = match days {
-            (None, None) => 1,
-            (Some(_), None) => {
-                bail!("Can't infer day of month from weekday");
+    /// The next occurrence in `zone` from now, in UTC
+    pub fn next_utc(&self, zone: &Zone) -> Option<DateTime<Utc>> {
+        self.next(zone.now()).and_then(|d| zone.to_utc(d))
+    }
+
+    /// An iCalendar RRULE style rule, like `FREQ=WEEKLY;BYDAY=MO,FR;BYHOUR=9;BYMINUTE=30`
+    pub fn rule(&self) -> String {
+        let join = |doms: &[u32]| doms.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        let freq = match &self.freq {
+            Frequency::Weekly(days) => {
+                let days = days.iter().map(|d| RRULE_DAYS[d.num_days_from_monday() as usize]);
+                format!("FREQ=WEEKLY;BYDAY={}", days.collect::<Vec<_>>().join(","))
+            }
+            Frequency::Monthly(doms) => format!("FREQ=MONTHLY;BYMONTHDAY={}", join(doms)),
+            Frequency::Yearly(month, doms) => {
+                format!("FREQ=YEARLY;BYMONTH={month};BYMONTHDAY={}", join(doms))
             }
-            (_, Some(dom)) => dom.as_str().parse::<u32>()?,
         };
-        date = date.with_day(dom).ok_or(anyhow!("Incompatible Day"))?;
+        format!("{freq};BYHOUR={};BYMINUTE={}", self.time.hour(), self.time.minute())
+    }
 
-        if let Some(day) = days.0 {
-            if !weekday_string(date.weekday()).starts_with(&day.as_str().to_lowercase()) {
-                bail!("Weekday doesn't match day of month");
-            }
-        }
+    /// Read back a rule written by [Self::rule]
+    pub fn from_rule(rule: &str) -> Result<Recurrence> {
+        let parts: Vec<(&str, &str)> = rule.split(';').filter_map(|p| p.split_once('=')).collect();
+        let get = |key: &str| {
+            parts
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| *v)
+                .ok_or(anyhow!("rule is missing {key}"))
+        };
+        let num = |key: &str| -> Result<u32> { Ok(get(key)?.parse()?) };
+        let nums = |key: &str| -> Result<Vec<u32>> {
+            get(key)?.split(',').map(|n| -> Result<u32> { Ok(n.parse()?) }).collect()
+        };
 
-        info!("Parsed {s} into {date:?}");
-        return Ok(Utc.from_local_datetime(&date).unwrap());
+        let freq = match get("FREQ")? {
+            "WEEKLY" => Frequency::Weekly(
+                get("BYDAY")?
+                    .split(',')
+                    .map(|d| {
+                        RRULE_DAYS
+                            .iter()
+                            .position(|x| *x == d)
+                            .and_then(|i| Weekday::try_from(i as u8).ok())
+                            .ok_or(anyhow!("Unknown day {d}"))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            "MONTHLY" => Frequency::Monthly(nums("BYMONTHDAY")?),
+            "YEARLY" => Frequency::Yearly(num("BYMONTH")?, nums("BYMONTHDAY")?),
+            f => bail!("Unknown frequency {f}"),
+        };
+        let time = NaiveTime::from_hms_opt(num("BYHOUR")?, num("BYMINUTE")?, 0)
+            .ok_or(anyhow!("Incompatible Time"))?;
+        Ok(Recurrence { freq, time })
     }
+}
 
-    bail!("date string doesn't match date regex")
+#[cfg(test)]
+fn test_date(s: &str) -> String {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .unwrap()
+        .and_local_timezone(Local)
+        .unwrap()
+        .with_timezone(&Utc)
+        .to_string()
 }
 
 #[test]
 fn vaild_date_parsing() {
+    // a Wednesday
+    let today = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap();
     let examples = [
-        "Thursday, 29 October 2020 16:20.10",
-        "Thurs, 29 Oct 2020 12:43.31 GMT",
-        "We 12th Jan 2022",
-        "Friday, 03 Jan 2025 13:44.30 EST",
-        "Wed 1st Jan 2025",
-        "Wed 1st Jan", // NOTE, this case will break in 2026. I sure hope we implement proper
-        // repetition parsing before then :D
-        "1st Jan 2025",
-        "1st Jan 2025 12:54 EST",
+        ("Thursday, 29 October 2020 16:20.10", "2020-10-29 16:20:10"),
+        ("Thurs, 29 Oct 2020 12:43.31 GMT", "2020-10-29 12:43:31 UTC"),
+        ("We 12th Jan 2022", "2022-01-12 00:00:00"),
+        ("Friday, 03 Jan 2025 13:44.30 EST", "2025-01-03 18:44:30 UTC"),
+        ("Wed 1st Jan 2025", "2025-01-01 00:00:00"),
+        ("Wed 1st Jan", "2025-01-01 00:00:00"),
+        ("1st Jan 2025", "2025-01-01 00:00:00"),
+        ("1st Jan 2025 12:54 EST", "2025-01-01 17:54:00 UTC"),
     ];
-    examples
-        .iter()
-        .map(|s| format!("{:?}", parse(s).unwrap()))
-        .collect::<Vec<_>>()
-        .join("\n");
+    for (s, expected) in examples {
+        let expected = match expected.strip_suffix(" UTC") {
+            Some(_) => expected.to_string(),
+            None => test_date(expected),
+        };
+        let parsed = parse(s, today, &DateOptions::default()).unwrap();
+        assert_eq!(parsed.to_string(), expected, "{s}");
+    }
+}
+
+#[test]
+fn partial_dates() {
+    let date = |s: &str, today: &str| {
+        let today = NaiveDate::parse_from_str(today, "%Y-%m-%d").unwrap();
+        parse(s, today, &DateOptions::default()).unwrap().to_string()
+    };
+    let midnight = |s: &str| test_date(&format!("{s} 00:00:00"));
+
+    // no year, the next time the date comes around
+    assert_eq!(date("1st Jan", "2026-10-18"), midnight("2027-01-01"));
+    assert_eq!(date("18th Oct", "2026-10-18"), midnight("2026-10-18"));
+    assert_eq!(date("29th Feb", "2026-10-18"), midnight("2028-02-29"));
+    // ...that also falls on the weekday
+    assert_eq!(date("Wed 1st Jan", "2026-10-18"), midnight("2031-01-01"));
+    // no month, the next month that has the day
+    assert_eq!(date("5th", "2025-01-01"), midnight("2025-01-05"));
+    assert_eq!(date("5th", "2025-01-06"), midnight("2025-02-05"));
+    assert_eq!(date("31st", "2025-02-01"), midnight("2025-03-31"));
+    assert_eq!(date("Fri 13th", "2025-01-01"), midnight("2025-06-13"));
+    // just a weekday
+    assert_eq!(date("Friday", "2025-01-01"), midnight("2025-01-03"));
+    assert_eq!(date("Wed", "2025-01-01"), midnight("2025-01-01"));
+    // just a month, the first of the next one
+    assert_eq!(date("March", "2025-01-01"), midnight("2025-03-01"));
+    assert_eq!(date("Jan 2026", "2025-01-01"), midnight("2026-01-01"));
+    // time without seconds
+    assert_eq!(date("1st Jan 2025 9:30", "2025-01-01"), test_date("2025-01-01 09:30:00"));
 }
 
 #[test]
 fn invalid_date_parsing() {
+    let today = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap();
     let examples = [
         "Thursday, October 29th 2020", // month and day out of order
         "Friday, 29th October 2020",   // Weekday and day of month don't match
         "30st February 2020",          // Day of month that doesn't exist
+        "30th Feb",                    // no year has it either
+        "",
+        "Fri January", // can't pick a day of month from the weekday
     ];
 
-    examples.iter().for_each(|e| assert!(parse(e).is_err()))
+    examples
+        .iter()
+        .for_each(|e| assert!(parse(e, today, &DateOptions::default()).is_err(), "{e}"))
+}
+
+#[test]
+fn lenient_dates() {
+    let today = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap(); // a Wednesday
+    let lenient = DateOptions {
+        strictness: Strictness::Lenient,
+        ..Default::default()
+    };
+    let date = |s: &str| parse(s, today, &lenient).unwrap().to_string();
+
+    // not in the spec, so strict mode doesn't take them
+    for s in ["Jan 1 2025", "2025-01-01", "tomorrow", "next friday"] {
+        assert!(parse(s, today, &DateOptions::default()).is_err(), "{s}");
+    }
+
+    assert_eq!(date("Jan 1 2025"), test_date("2025-01-01 00:00:00"));
+    assert_eq!(date("Wed, January 1st, 2025 10:30"), test_date("2025-01-01 10:30:00"));
+    assert_eq!(date("Feb 14"), test_date("2025-02-14 00:00:00"));
+    assert_eq!(date("Jan 1 2025 12:54 EST"), "2025-01-01 17:54:00 UTC");
+    assert_eq!(date("2025-01-01"), test_date("2025-01-01 00:00:00"));
+    assert_eq!(date("2025-01-01T10:00"), test_date("2025-01-01 10:00:00"));
+    assert_eq!(date("2025-01-01T10:00:30Z"), "2025-01-01 10:00:30 UTC");
+    assert_eq!(date("2025-01-01 10:00+02:00"), "2025-01-01 08:00:00 UTC");
+    assert_eq!(date("today"), test_date("2025-01-01 00:00:00"));
+    assert_eq!(date("Tomorrow 9:00"), test_date("2025-01-02 09:00:00"));
+    assert_eq!(date("next friday"), test_date("2025-01-03 00:00:00"));
+    // next week's, not today
+    assert_eq!(date("next Wed"), test_date("2025-01-08 00:00:00"));
+    assert_eq!(date("in 2 weeks"), test_date("2025-01-15 00:00:00"));
+    assert_eq!(date("in 3 days"), test_date("2025-01-04 00:00:00"));
+    assert_eq!(date("In 1 week 9:00"), test_date("2025-01-08 09:00:00"));
+    // the spec's formats still work
+    assert_eq!(date("1st Jan 2025"), test_date("2025-01-01 00:00:00"));
+
+    // too far away, and too many days to even count
+    let far = [
+        "in 3000000000000000000 weeks",
+        "in 99999999999 days",
+        "in 99999999999999999999 days",
+    ];
+    for s in ["Jan 32 2025", "2025-02-30", "next funday", "soon"].into_iter().chain(far) {
+        assert!(parse(s, today, &lenient).is_err(), "{s}");
+    }
+}
+
+#[test]
+fn time_zones() {
+    let utc = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap().and_utc();
+    let opts = DateOptions::default();
+    let today = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap();
+
+    assert_eq!(parse("1st Jan 2025 12:54 EST", today, &opts).unwrap(), utc("2025-01-01 17:54"));
+    // abbreviations are a fixed offset, even when the zone they belong to wouldn't use it
+    assert_eq!(parse("1st Jul 2025 12:54 EST", today, &opts).unwrap(), utc("2025-07-01 17:54"));
+    assert_eq!(parse("1st Jul 2025 12:00 CET", today, &opts).unwrap(), utc("2025-07-01 11:00"));
+    // named zones follow DST
+    let ny = |s: &str| parse(&format!("{s} America/New_York"), today, &opts).unwrap();
+    assert_eq!(ny("1st Jan 2025 12:00"), utc("2025-01-01 17:00"));
+    assert_eq!(ny("1st Jul 2025 12:00"), utc("2025-07-01 16:00"));
+    // skipped when the clocks go forward, read with the offset from before the gap
+    assert_eq!(ny("9th Mar 2025 02:30"), utc("2025-03-09 07:30"));
+    assert_eq!(ny("9th Mar 2025 02:07"), utc("2025-03-09 07:07"));
+    // a half hour gap
+    let lord_howe = Zone::parse("Australia/Lord_Howe").unwrap();
+    let gap = NaiveDate::from_ymd_opt(2025, 10, 5).unwrap().and_hms_opt(2, 10, 0).unwrap();
+    assert_eq!(lord_howe.to_utc(gap), Some(utc("2025-10-04 15:40")));
+    // happens twice when the clocks go back, the first one is used
+    assert_eq!(ny("2nd Nov 2025 01:30"), utc("2025-11-02 05:30"));
+
+    let paris = DateOptions {
+        timezone: Zone::parse("Europe/Paris").unwrap(),
+        ..Default::default()
+    };
+    assert_eq!(parse("1st Jan 2025 12:00", today, &paris).unwrap(), utc("2025-01-01 11:00"));
+    assert_eq!(parse("1st Jan 2025 12:00 UTC", today, &paris).unwrap(), utc("2025-01-01 12:00"));
+
+    assert!(Zone::parse("Not/A_Zone").is_err());
+    assert!(parse("1st Jan 2025 12:00 XYZ", today, &opts).is_err());
+}
+
+#[test]
+fn recurrence_parsing() {
+    let at = |y, m, d, h| NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(h, 0, 0).unwrap();
+    // a Wednesday
+    let now = at(2025, 1, 15, 12);
+
+    let r = parse_recurrence("Fri").unwrap();
+    assert_eq!(r.freq, Frequency::Weekly(vec![Weekday::Fri]));
+    assert_eq!(r.next(now), Some(at(2025, 1, 17, 0)));
+
+    let r = parse_recurrence("Mon-Fri 9:30").unwrap();
+    assert_eq!(r.rule(), "FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR;BYHOUR=9;BYMINUTE=30");
+    // already past 9:30 today
+    assert_eq!(r.next(now), Some(at(2025, 1, 16, 9) + chrono::Duration::minutes(30)));
+
+    let r = parse_recurrence("Sat-Mon").unwrap();
+    assert_eq!(r.freq, Frequency::Weekly(vec![Weekday::Sat, Weekday::Sun, Weekday::Mon]));
+
+    let r = parse_recurrence("15th").unwrap();
+    assert_eq!(r.freq, Frequency::Monthly(vec![15]));
+    assert_eq!(r.next(now), Some(at(2025, 2, 15, 0)));
+    assert_eq!(r.next(at(2025, 1, 15, 0)), Some(at(2025, 1, 15, 0)));
+
+    let r = parse_recurrence("31st").unwrap();
+    assert_eq!(r.next(at(2025, 2, 1, 0)), Some(at(2025, 3, 31, 0)));
+
+    let r = parse_recurrence("29th Feb").unwrap();
+    assert_eq!(r.freq, Frequency::Yearly(2, vec![29]));
+    assert_eq!(r.next(now), Some(at(2028, 2, 29, 0)));
+
+    let r = parse_recurrence("1st-3rd").unwrap();
+    assert_eq!(r.freq, Frequency::Monthly(vec![1, 2, 3]));
+    assert_eq!(r.rule(), "FREQ=MONTHLY;BYMONTHDAY=1,2,3;BYHOUR=0;BYMINUTE=0");
+    assert_eq!(r.next(now), Some(at(2025, 2, 1, 0)));
+
+    let r = parse_recurrence("24th - 26th Dec 18:00").unwrap();
+    assert_eq!(r.freq, Frequency::Yearly(12, vec![24, 25, 26]));
+    assert_eq!(r.next(at(2025, 12, 25, 19)), Some(at(2025, 12, 26, 18)));
+
+    for r in ["Mon-Fri 9:30", "3rd", "1st Jan 12:00", "1st-3rd", "24th-26th Dec"] {
+        let r = parse_recurrence(r).unwrap();
+        assert_eq!(Recurrence::from_rule(&r.rule()).unwrap(), r);
+    }
+
+    for bad in ["1st Jan 2025", "Funday", "30th Feb", "", "32nd", "25th-5th", "28th-30th Feb"] {
+        assert!(parse_recurrence(bad).is_err(), "{bad}");
+    }
 }
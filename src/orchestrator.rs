@@ -1,21 +1,87 @@
+use crate::db::DocState;
 use crate::DatabaseConnection;
-use crate::doc_parser::ParsedDocument;
-use anyhow::Result;
-use chrono::DateTime;
-use chrono::Utc;
-use ignore::DirEntry;
+use crate::doc_parser::{self, ParsedDocument};
+use crate::norg_date::DateOptions;
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{DirEntry, Match};
 use ignore::{types::TypesBuilder, WalkBuilder};
-use log::info;
-use std::convert::identity;
-use std::time;
-use std::{path::Path, sync::mpsc::channel};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{self, SystemTime};
+use tokio::sync::{mpsc, Semaphore};
 
-/// Parse all the files in the workspace, skipping files that were last edited within a few ms of
-/// the edited time we have for them. Streams reading, parsing, and inserting steps together
+/// Options that control how we index, these are passed in through `init`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IndexOptions {
+    /// Number of blocking workers that parse documents in parallel
+    pub workers: usize,
+    /// Number of documents the insert job writes per transaction. Each document still gets its own
+    /// savepoint, so a document that fails to write doesn't affect the rest of its batch
+    pub batch_size: usize,
+    /// Root of the workspace that `$/` links are resolved against. Comes from `init`, not from the
+    /// options table
+    #[serde(skip)]
+    pub workspace: Option<PathBuf>,
+    /// Name of the workspace, defaults to the name of its directory
+    pub workspace_name: Option<String>,
+    /// Store the plain text of every paragraph for full text search. This makes the database a lot
+    /// larger, so it's opt in
+    pub full_text: bool,
+    /// Views to create on startup, name -> SELECT statement
+    pub views: HashMap<String, String>,
+    /// Make `views` temp views instead of storing them in the database
+    pub temp_views: bool,
+    #[serde(flatten)]
+    pub dates: DateOptions,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        IndexOptions {
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            batch_size: 1,
+            workspace: None,
+            workspace_name: None,
+            full_text: false,
+            views: HashMap::new(),
+            temp_views: false,
+            dates: DateOptions::default(),
+        }
+    }
+}
+
+/// Parse all the files in the workspace, skipping files whose contents hash to what we have for
+/// them (or whose mtime hasn't moved at all). Streams reading, parsing, and inserting together:
+/// - the walker runs on its own blocking thread, and feeds every norg file it finds to us
+/// - we decide which files need parsing, and hand those off to a bounded pool of blocking workers
+/// - a single insert job receives parsed documents and writes them to the database
+///
+/// Docs that we have stored under `path` but no longer exist on disk are removed afterwards, except
+/// under directories that we couldn't walk. Returns the number of removed docs
 pub async fn index_workspace(
     path: &Path,
     conn: &DatabaseConnection,
-) -> Result<()> {
+    opts: &IndexOptions,
+) -> Result<usize> {
+    let removed = index_tree(path, conn, opts).await?;
+    conn.refresh_recurring(&opts.dates.timezone).await?;
+    Ok(removed)
+}
+
+/// [index_workspace] for just the directory at `path`, without the workspace wide upkeep (moving
+/// recurring tasks on). Only the docs stored under `path` are looked at
+pub async fn index_tree(
+    path: &Path,
+    conn: &DatabaseConnection,
+    opts: &IndexOptions,
+) -> Result<usize> {
     info!("Indexing {path:?}\n...");
 
     let start = time::Instant::now();
@@ -24,86 +90,379 @@ pub async fn index_workspace(
     types.add("norg", "*.norg")?;
     let types = types.build()?;
 
-    let (tx, rx) = channel::<Option<ParsedDocument>>();
+    let workers = opts.workers.max(1);
+    let (entry_tx, mut entry_rx) = mpsc::channel::<(PathBuf, Option<i64>)>(workers * 4);
+    let (doc_tx, mut doc_rx) = mpsc::channel::<ParsedDocument>(workers * 4);
 
     let x = conn.clone();
+    let batch_size = opts.batch_size.max(1);
     let insert_job = tokio::spawn(async move {
         info!("insert job waiting");
-        for doc in rx {
-            if let Some(mut doc) = doc {
-                x.clone().insert_or_update_doc(&mut doc).await.unwrap();
-            } else {
-                break;
+        let mut batch = Vec::with_capacity(batch_size);
+        // we don't wait for a batch to fill up, whatever is ready is written together
+        while doc_rx.recv_many(&mut batch, batch_size).await > 0 {
+            match x.insert_or_update_docs(&mut batch).await {
+                Ok(results) => {
+                    for (doc, res) in batch.iter().zip(results) {
+                        if let Err(e) = res {
+                            error!("Failed to insert {:?}: {e:?}", doc.path);
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to write batch of {} docs: {e:?}", batch.len()),
             }
+            batch.clear();
         }
     });
 
-    // WalkBuilder::new(workspace_path).types(types).build_parallel().run(|| Box::new(|path| {
-    //     if let Ok(path) = path {
-    //         if let Ok(doc) = ParsedDocument::new(&path.path().to_string_lossy()) {
-    //             let _ = tx.send(doc);
-    //         };
-    //     }
-    //     WalkState::Continue
-    // }));
-    info!("Walking..");
-    for entry in WalkBuilder::new(path)
-        .types(types)
-        .build()
-        .flatten()
-    {
-        let path = entry.path();
-        if path.is_dir() || path.extension().is_none_or(|ext| ext != "norg") {
-            continue;
+    let root = path.to_path_buf();
+    let walk_job = tokio::task::spawn_blocking(move || {
+        info!("Walking..");
+        // we can't tell what's under a directory we failed to read, so nothing there is pruned
+        let mut failed = vec![];
+        // hidden and ignored files are skipped, `index_file` has to agree with this, see [ignored]
+        let walker = WalkBuilder::new(&root).types(types).build();
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Failed to walk: {e}");
+                    failed.push(error_path(&e).unwrap_or(&root).to_path_buf());
+                    continue;
+                }
+            };
+            let path = entry.path();
+            if path.is_dir() || path.extension().is_none_or(|ext| ext != "norg") {
+                continue;
+            }
+            // the receiver only goes away if indexing failed, no reason to keep walking
+            if entry_tx
+                .blocking_send((path.to_path_buf(), modified_time(&entry)))
+                .is_err()
+            {
+                break;
+            }
         }
-        let path = path.to_string_lossy();
-        let stored_edit_time = conn.get_updated_date(&path).await;
+        info!("Done walking");
+        failed
+    });
+
+    let mut states = conn.doc_states(path).await?;
+    // files that were touched without changing, we only need to store their new mtime
+    let touched = Arc::new(Mutex::new(vec![]));
+    let pool = Arc::new(Semaphore::new(workers));
+    let mut seen = HashSet::new();
+    while let Some((path, modified)) = entry_rx.recv().await {
+        let path = path.to_string_lossy().to_string();
+        seen.insert(path.clone());
+        let state = states.remove(&path);
 
-        if stored_edit_time.is_ok_and(|t| !should_parse(&entry, t).is_ok_and(identity)) {
+        if state.as_ref().is_some_and(|s| untouched(s, modified)) {
             info!("Skipping {path:?}");
             continue;
         };
 
-        info!("Parsing {path:?}");
-        // TODO: this parsing step is expensive, should spawn it into a task probably. But that
-        // creates some lifetime problem
-        if let Ok(doc) = ParsedDocument::new(&path) {
-            tx.send(Some(doc)).unwrap();
-        };
+        // waiting on a permit here is what keeps the number of parsing workers bounded
+        let permit = pool.clone().acquire_owned().await?;
+        let doc_tx = doc_tx.clone();
+        let touched = touched.clone();
+        let opts = opts.clone();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let Ok(contents) = fs::read_to_string(&path) else {
+                return;
+            };
+            let hash = doc_parser::content_hash(&contents);
+            if state.is_some_and(|s| !s.stale && s.hash.as_ref() == Some(&hash)) {
+                info!("Unchanged {path:?}");
+                touched.lock().unwrap_or_else(|e| e.into_inner()).push((path, modified));
+                return;
+            }
+
+            info!("Parsing {path:?}");
+            let mut doc = ParsedDocument::from_source(&path, &contents, &opts.dates);
+            doc.mtime = modified;
+            prepare(&mut doc, &opts);
+            let _ = doc_tx.blocking_send(doc);
+        });
     }
-    info!("Done walking");
-    // this is the way we tell the insert job to stop listening
-    tx.send(None).unwrap();
+    let failed = walk_job.await.unwrap_or_else(|_| vec![path.to_path_buf()]);
 
+    // each worker holds its own sender, the insert job stops once the last of them is done
+    drop(doc_tx);
     let _ = insert_job.await;
 
+    let touched = std::mem::take(&mut *touched.lock().unwrap_or_else(|e| e.into_inner()));
+    conn.touch_docs(&touched).await?;
+
+    let removed = prune(path, &seen, &failed, conn).await?;
+
     let end = time::Instant::now();
     info!("Index time: {:?}", end - start);
 
-    Ok(())
+    Ok(removed)
+}
+
+/// Remove any docs stored under `root` that weren't seen while walking it. This is how deleted and
+/// moved files leave the index. Docs under `failed` (paths the walk couldn't read) are kept.
+/// Returns the number of removed docs
+async fn prune(
+    root: &Path,
+    seen: &HashSet<String>,
+    failed: &[PathBuf],
+    conn: &DatabaseConnection,
+) -> Result<usize> {
+    let orphans = conn
+        .docs_under(root)
+        .await?
+        .into_iter()
+        .filter(|(_, path)| !seen.contains(path))
+        .filter(|(_, path)| !failed.iter().any(|f| Path::new(path).starts_with(f)))
+        .map(|(id, path)| {
+            info!("Removing {path:?}");
+            id
+        })
+        .collect::<Vec<_>>();
+
+    conn.remove_docs(&orphans).await?;
+
+    Ok(orphans.len())
+}
+
+/// Remove every doc stored under `root`, which can be a single file. Returns the number of removed
+/// docs
+pub async fn remove_under(root: &Path, conn: &DatabaseConnection) -> Result<usize> {
+    prune(root, &HashSet::new(), &[], conn).await
+}
+
+/// Where a walk error happened, None if the error doesn't say
+fn error_path(e: &ignore::Error) -> Option<&Path> {
+    match e {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        _ => None,
+    }
+}
+
+/// Whether `path` is a file that the walk in [index_workspace] would index: a norg file that isn't
+/// [ignored]. Files indexed on their own have to follow the same rules, otherwise the next full
+/// index prunes them again
+pub fn indexable(path: &Path, root: Option<&Path>) -> bool {
+    path.extension().is_some_and(|ext| ext == "norg") && !ignored(path, root)
+}
+
+/// Whether the walk skips `path`, b/c it or one of the directories between it and `root` is hidden,
+/// or matches a `.ignore`, `.gitignore`, `.git/info/exclude` or the global gitignore. These are
+/// the walker's standard filters, checked one entry at a time the way it does. Outside of the
+/// workspace only the path itself counts
+pub fn ignored(path: &Path, root: Option<&Path>) -> bool {
+    let entries: Vec<&Path> = match root.filter(|r| path.starts_with(r) && path != *r) {
+        Some(root) => path.ancestors().take_while(|p| *p != root).collect(),
+        None => vec![path],
+    };
+    // the walker never goes into an ignored directory, so check from the top down
+    entries
+        .iter()
+        .rev()
+        .any(|entry| entry_ignored(entry, *entry != path || path.is_dir()))
+}
+
+/// [ignored] for just this entry. Like the walker, `.ignore` files win over `.gitignore` files,
+/// which win over the excludes, and deeper files win over the ones above them
+fn entry_ignored(entry: &Path, is_dir: bool) -> bool {
+    if entry
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+    {
+        return true;
+    }
+
+    let dirs: Vec<&Path> = entry.ancestors().skip(1).collect();
+    let matched = |gi: &Gitignore| match gi.matched(entry, is_dir) {
+        Match::None => None,
+        m => Some(m.is_ignore()),
+    };
+    let in_dirs = |name: &str| {
+        dirs.iter()
+            .find_map(|dir| matched(&ignore_file(dir, &dir.join(name))))
+    };
+
+    let mut found = in_dirs(".ignore");
+    // git's ignore rules only count inside of a repo
+    if let Some(repo) = dirs.iter().find(|dir| dir.join(".git").exists()) {
+        found = found
+            .or_else(|| in_dirs(".gitignore"))
+            .or_else(|| matched(&ignore_file(repo, &repo.join(".git/info/exclude"))))
+            .or_else(|| matched(&Gitignore::global().0));
+    }
+    found.unwrap_or(false)
+}
+
+/// The rules in the ignore file at `file`, relative to `dir`. Empty if it doesn't exist
+fn ignore_file(dir: &Path, file: &Path) -> Gitignore {
+    if !file.is_file() {
+        return Gitignore::empty();
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(file) {
+        warn!("Failed to read {file:?}: {e}");
+    }
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
 }
 
 // index a single file
 pub async fn index_file(
     path: &Path,
     conn: &DatabaseConnection,
+    opts: &IndexOptions,
 ) -> Result<()> {
-    assert!(path.is_file());
-
-    if let Ok(mut doc) = ParsedDocument::new(path.to_str().unwrap()) {
-        info!("{doc:?}");
-        conn.insert_or_update_doc(&mut doc).await?;
+    if !path.is_file() {
+        let e = io::Error::new(io::ErrorKind::NotFound, format!("{path:?} isn't a file"));
+        return Err(e.into());
+    }
+    if !indexable(path, opts.workspace.as_deref()) {
+        info!("Not indexing {path:?}, it's ignored or isn't a norg file");
+        return Ok(());
     }
 
+    let mut doc = ParsedDocument::new(&path.to_string_lossy(), &opts.dates)
+        .with_context(|| format!("couldn't read {path:?}"))?;
+    doc.mtime = fs::metadata(path).and_then(|m| m.modified()).ok().and_then(millis);
+    prepare(&mut doc, opts);
+    info!("{doc:?}");
+    conn.insert_or_update_doc(&mut doc).await?;
+
     Ok(())
 }
 
-fn should_parse(entry: &DirEntry, updated: DateTime<Utc>) -> anyhow::Result<bool> {
-    let modified: i64 = entry
-        .metadata()?
-        .modified()?
-        .duration_since(std::time::UNIX_EPOCH)?
-        .as_secs() as i64;
+/// Finish up a freshly parsed doc before it's written
+fn prepare(doc: &mut ParsedDocument, opts: &IndexOptions) {
+    doc.resolve_links(opts.workspace.as_deref());
+    if !opts.full_text {
+        doc.paragraphs.clear();
+    }
+}
+
+/// Last modified time of the entry in milliseconds since the epoch
+fn modified_time(entry: &DirEntry) -> Option<i64> {
+    millis(entry.metadata().ok()?.modified().ok()?)
+}
+
+fn millis(t: SystemTime) -> Option<i64> {
+    Some(t.duration_since(time::UNIX_EPOCH).ok()?.as_millis() as i64)
+}
+
+/// The file's mtime is exactly what it was when we last read it, so we don't even need to look at
+/// its contents. Anything else (including files we couldn't get an mtime for) gets hashed, mtimes
+/// are only a shortcut, they aren't trusted to say a file changed
+fn untouched(state: &DocState, modified: Option<i64>) -> bool {
+    !state.stale && state.hash.is_some() && modified.is_some() && state.mtime == modified
+}
+
+#[tokio::test]
+async fn prune_keeps_what_the_walk_missed() {
+    let dir = crate::db::util::test_dir("prune");
+    let conn = DatabaseConnection::new(&dir.join("test.sqlite")).await.unwrap();
+    let ws = dir.join("ws");
+    fs::create_dir_all(ws.join("unreadable")).unwrap();
+    // gitignores only count in a repo
+    fs::create_dir_all(ws.join(".git")).unwrap();
+    fs::write(ws.join(".gitignore"), "ignored.norg\n").unwrap();
+    for name in ["a.norg", "ignored.norg", "unreadable/b.norg"] {
+        fs::write(ws.join(name), "* heading\n").unwrap();
+    }
+    let opts = IndexOptions {
+        workspace: Some(ws.clone()),
+        ..IndexOptions::default()
+    };
+
+    index_workspace(&ws, &conn, &opts).await.unwrap();
+    let stored = |docs: Vec<(i64, String)>| -> HashSet<String> {
+        docs.into_iter().map(|(_, p)| p).collect()
+    };
+    let docs = stored(conn.docs_under(&ws).await.unwrap());
+    assert_eq!(docs.len(), 2);
+    assert!(!docs.contains(&ws.join("ignored.norg").to_string_lossy().to_string()));
+    // `index_file` skips what the walk skips, so the next index has nothing to prune
+    index_file(&ws.join("ignored.norg"), &conn, &opts).await.unwrap();
+    assert_eq!(stored(conn.docs_under(&ws).await.unwrap()), docs);
+    assert_eq!(index_workspace(&ws, &conn, &opts).await.unwrap(), 0);
+
+    // a walk that failed under `unreadable` doesn't take its docs with it
+    let seen: HashSet<String> = [ws.join("a.norg").to_string_lossy().to_string()].into();
+    let removed = prune(&ws, &seen, &[ws.join("unreadable")], &conn).await.unwrap();
+    assert_eq!(removed, 0);
+    assert_eq!(stored(conn.docs_under(&ws).await.unwrap()), docs);
+
+    // hidden files are skipped by both the walk and `index_file`
+    fs::create_dir_all(ws.join(".trash")).unwrap();
+    fs::write(ws.join(".trash/c.norg"), "* heading\n").unwrap();
+    index_file(&ws.join(".trash/c.norg"), &conn, &opts).await.unwrap();
+    assert_eq!(stored(conn.docs_under(&ws).await.unwrap()), docs);
+}
+
+#[tokio::test]
+async fn workers_index_every_doc_once() {
+    let dir = crate::db::util::test_dir("workers");
+    let conn = DatabaseConnection::new(&dir.join("test.sqlite")).await.unwrap();
+    let ws = dir.join("ws");
+    for d in 0..4 {
+        fs::create_dir_all(ws.join(format!("dir{d}"))).unwrap();
+        for f in 0..10 {
+            let path = ws.join(format!("dir{d}/note{f}.norg"));
+            fs::write(path, format!("* Note {d}-{f}\n  - ( ) task {f}\n")).unwrap();
+        }
+    }
+    let opts = IndexOptions {
+        workspace: Some(ws.clone()),
+        workers: 4,
+        batch_size: 3,
+        ..IndexOptions::default()
+    };
+
+    index_workspace(&ws, &conn, &opts).await.unwrap();
+    let mut rows = conn
+        .user_query(
+            "SELECT count(*), count(DISTINCT path), (SELECT count(*) FROM tasks) FROM docs",
+            (),
+        )
+        .await
+        .unwrap();
+    let row = rows.next().await.unwrap().unwrap();
+    let counts: (i64, i64, i64) = (row.get(0).unwrap(), row.get(1).unwrap(), row.get(2).unwrap());
+    assert_eq!(counts, (40, 40, 40));
+}
+
+#[tokio::test]
+async fn touched_files_keep_their_parse() {
+    let dir = crate::db::util::test_dir("touched");
+    let conn = DatabaseConnection::new(&dir.join("test.sqlite")).await.unwrap();
+    let ws = dir.join("ws");
+    fs::create_dir_all(&ws).unwrap();
+    let file = ws.join("a.norg");
+    fs::write(&file, "* heading\n").unwrap();
+    let opts = IndexOptions {
+        workspace: Some(ws.clone()),
+        ..IndexOptions::default()
+    };
+    index_workspace(&ws, &conn, &opts).await.unwrap();
+
+    // only a reparse would put the title back
+    conn.conn.execute("UPDATE docs SET title = 'marker'", ()).await.unwrap();
+    let db = &conn;
+    let stored = move || async move {
+        let mut rows = db.user_query("SELECT title, mtime FROM docs", ()).await.unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        (row.get::<String>(0).unwrap(), row.get::<i64>(1).unwrap())
+    };
 
-    Ok(modified - updated.timestamp() > 3)
+    let modified = time::UNIX_EPOCH + time::Duration::from_secs(1_000_000);
+    fs::write(&file, "* heading\n").unwrap();
+    let f = fs::File::options().write(true).open(&file).unwrap();
+    f.set_modified(modified).unwrap();
+    drop(f);
+    index_workspace(&ws, &conn, &opts).await.unwrap();
+    assert_eq!(stored().await, ("marker".to_string(), 1_000_000_000));
 }
@@ -0,0 +1,736 @@
+// A small query language for people who would rather not write SQL. It's loosely based on
+// obsidian's dataview:
+//
+//   TASKS FROM #work AND "projects" WHERE due < today AND status != done SORT due DESC LIMIT 10
+//   DOCS FROM #journal WHERE created >= 2025-01-01 SORT created
+//
+// Queries are compiled to SQL against the same tables that `#sql` blocks use, so results have the
+// same shape. Everything is parameterized, nothing the user writes ends up in the SQL itself.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::CharIndices;
+
+use chrono::{Days, NaiveDate, NaiveDateTime, NaiveTime};
+use libsql::Value;
+
+use crate::norg_date::{self, Zone};
+
+/// SQL and the positional parameters that go with it
+#[derive(Debug)]
+pub struct Compiled {
+    pub sql: String,
+    pub params: Vec<Value>,
+}
+
+/// Where in the query something went wrong. Lines and columns are 1-indexed, columns count chars
+#[derive(Debug, PartialEq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.message)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// What a query needs to know that isn't in the query
+pub struct QueryContext<'a> {
+    /// relative `FROM` paths are relative to this
+    pub workspace: Option<&'a Path>,
+    /// zone that `today` and date literals are in
+    pub zone: &'a Zone,
+    /// now, in `zone`
+    pub now: NaiveDateTime,
+}
+
+pub fn compile(source: &str, ctx: &QueryContext) -> Result<Compiled, SyntaxError> {
+    let tokens = lex(source).map_err(|(pos, message)| error_at(source, pos, message))?;
+    let mut parser = Parser {
+        tokens,
+        i: 0,
+        ctx,
+        params: vec![],
+    };
+    parser
+        .query()
+        .map_err(|(pos, message)| error_at(source, pos, message))
+}
+
+fn error_at(source: &str, pos: usize, message: String) -> SyntaxError {
+    let before = &source[..pos.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    SyntaxError {
+        line,
+        col: before[line_start..].chars().count() + 1,
+        message,
+    }
+}
+
+/// Byte offset into the source, and what went wrong there
+type Failure = (usize, String);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Word(String),
+    Str(String),
+    Num(i64),
+    Date(NaiveDate),
+    /// `#category`
+    Tag(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+    Minus,
+    Eof,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Word(w) => write!(f, "`{w}`"),
+            Tok::Str(s) => write!(f, "\"{s}\""),
+            Tok::Num(n) => write!(f, "`{n}`"),
+            Tok::Date(d) => write!(f, "`{d}`"),
+            Tok::Tag(t) => write!(f, "`#{t}`"),
+            Tok::Op(op) => write!(f, "`{op}`"),
+            Tok::LParen => write!(f, "`(`"),
+            Tok::RParen => write!(f, "`)`"),
+            Tok::Comma => write!(f, "`,`"),
+            Tok::Minus => write!(f, "`-`"),
+            Tok::Eof => write!(f, "the end of the query"),
+        }
+    }
+}
+
+/// Consume chars while `f` holds, returning them
+fn take_while(chars: &mut Peekable<CharIndices>, f: impl Fn(char) -> bool) -> String {
+    let mut s = String::new();
+    while let Some((_, c)) = chars.next_if(|&(_, c)| f(c)) {
+        s.push(c);
+    }
+    s
+}
+
+fn lex(source: &str) -> Result<Vec<(Tok, usize)>, Failure> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let tok = match c {
+            '(' | ')' | ',' | '-' | '~' | '=' => {
+                chars.next();
+                match c {
+                    '(' => Tok::LParen,
+                    ')' => Tok::RParen,
+                    ',' => Tok::Comma,
+                    '-' => Tok::Minus,
+                    '~' => Tok::Op("~"),
+                    _ => Tok::Op("="),
+                }
+            }
+            '<' | '>' | '!' => {
+                chars.next();
+                let eq = chars.next_if(|&(_, c)| c == '=').is_some();
+                match (c, eq) {
+                    ('<', false) => Tok::Op("<"),
+                    ('<', true) => Tok::Op("<="),
+                    ('>', false) => Tok::Op(">"),
+                    ('>', true) => Tok::Op(">="),
+                    ('!', true) => Tok::Op("!="),
+                    _ => return Err((pos, "expected `!=`".to_string())),
+                }
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => s.push(c),
+                            None => return Err((pos, "unterminated string".to_string())),
+                        },
+                        Some((_, q)) if q == c => break,
+                        Some((_, c)) => s.push(c),
+                        None => return Err((pos, "unterminated string".to_string())),
+                    }
+                }
+                Tok::Str(s)
+            }
+            '#' => {
+                chars.next();
+                let name = take_while(&mut chars, |c| !c.is_whitespace() && !"(),".contains(c));
+                if name.is_empty() {
+                    return Err((pos, "expected a category name after `#`".to_string()));
+                }
+                Tok::Tag(name)
+            }
+            c if c.is_ascii_digit() => {
+                let s = take_while(&mut chars, |c| c.is_ascii_digit() || c == '-');
+                if let Ok(n) = s.parse() {
+                    Tok::Num(n)
+                } else if let Ok(d) = NaiveDate::parse_from_str(&s, "%Y-%m-%d") {
+                    Tok::Date(d)
+                } else {
+                    return Err((pos, format!("`{s}` isn't a number or a YYYY-MM-DD date")));
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                Tok::Word(take_while(&mut chars, |c| c.is_alphanumeric() || c == '_'))
+            }
+            c => return Err((pos, format!("unexpected `{c}`"))),
+        };
+        tokens.push((tok, pos));
+    }
+    tokens.push((Tok::Eof, source.len()));
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Int,
+    /// UTC `YYYY-MM-DD HH:MM:SS` text, see [norg_date::SQL_FORMAT]
+    Date,
+}
+
+struct Field {
+    name: &'static str,
+    column: &'static str,
+    kind: Kind,
+}
+
+const fn field(name: &'static str, column: &'static str, kind: Kind) -> Field {
+    Field { name, column, kind }
+}
+
+const TASK_FIELDS: &[Field] = &[
+    field("text", "t.text", Kind::Text),
+    field("status", "t.status", Kind::Text),
+    field("priority", "t.priority", Kind::Text),
+    field("due", "t.due", Kind::Date),
+    field("starts", "t.starts", Kind::Date),
+    field("recurs", "t.recurs", Kind::Date),
+    field("timestamp", "t.timestamp", Kind::Date),
+    field("created", "t.created", Kind::Date),
+    field("updated", "t.updated", Kind::Date),
+    field("kind", "t.kind", Kind::Text),
+    field("level", "t.level", Kind::Int),
+    field("line", "t.start_line", Kind::Int),
+    field("path", "d.path", Kind::Text),
+    field("title", "d.title", Kind::Text),
+];
+
+const DOC_FIELDS: &[Field] = &[
+    field("path", "d.path", Kind::Text),
+    field("title", "d.title", Kind::Text),
+    field("description", "d.description", Kind::Text),
+    field("authors", "d.authors", Kind::Text),
+    field("created", "d.created", Kind::Date),
+    field("updated", "d.updated", Kind::Date),
+    field("indexed", "d.indexed", Kind::Date),
+    field("lines", "d.line_count", Kind::Int),
+];
+
+/// Keywords can't be used as bare words
+const KEYWORDS: &[&str] = &[
+    "tasks", "docs", "from", "where", "sort", "limit", "and", "or", "not", "asc", "desc", "null",
+];
+
+enum Operand {
+    Field(&'static Field),
+    Text(String),
+    Int(i64),
+    Date(NaiveDate),
+    Now,
+    Null,
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Tok, usize)>,
+    i: usize,
+    ctx: &'a QueryContext<'a>,
+    params: Vec<Value>,
+}
+
+type PResult<T> = Result<T, Failure>;
+
+impl Parser<'_> {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.i].0
+    }
+
+    fn pos(&self) -> usize {
+        self.tokens[self.i].1
+    }
+
+    fn next(&mut self) -> (Tok, usize) {
+        let t = self.tokens[self.i].clone();
+        if t.0 != Tok::Eof {
+            self.i += 1;
+        }
+        t
+    }
+
+    fn is_keyword(&self, kw: &str) -> bool {
+        matches!(self.peek(), Tok::Word(w) if w.eq_ignore_ascii_case(kw))
+    }
+
+    fn eat_keyword(&mut self, kw: &str) -> bool {
+        let is = self.is_keyword(kw);
+        if is {
+            self.next();
+        }
+        is
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> PResult<T> {
+        Err((self.pos(), format!("expected {expected}, found {}", self.peek())))
+    }
+
+    /// Add a parameter, returning its placeholder
+    fn param(&mut self, v: impl Into<Value>) -> String {
+        self.params.push(v.into());
+        format!("?{}", self.params.len())
+    }
+
+    fn query(&mut self) -> PResult<Compiled> {
+        let (fields, select, default_order) = if self.eat_keyword("tasks") {
+            (
+                TASK_FIELDS,
+                "SELECT t.*, d.path, d.title FROM tasks t JOIN docs d ON d.id = t.file_id",
+                "d.path, t.start_line",
+            )
+        } else if self.eat_keyword("docs") {
+            (DOC_FIELDS, "SELECT d.* FROM docs d", "d.path")
+        } else {
+            return self.unexpected("`TASKS` or `DOCS`");
+        };
+
+        let mut conditions = vec![];
+        if self.eat_keyword("from") {
+            conditions.push(self.source_or()?);
+        }
+        if self.eat_keyword("where") {
+            conditions.push(self.expr_or(fields)?);
+        }
+
+        let mut order = vec![];
+        if self.eat_keyword("sort") {
+            loop {
+                let field = self.field(fields)?;
+                let dir = if self.eat_keyword("desc") {
+                    "DESC"
+                } else {
+                    self.eat_keyword("asc");
+                    "ASC"
+                };
+                // things without a value go last either way
+                order.push(format!("{0} IS NULL, {0} {dir}", field.column));
+                if *self.peek() != Tok::Comma {
+                    break;
+                }
+                self.next();
+            }
+        }
+        order.push(default_order.to_string());
+
+        let mut limit = String::new();
+        if self.eat_keyword("limit") {
+            match self.next() {
+                (Tok::Num(n), _) => limit = format!(" LIMIT {}", self.param(n)),
+                (t, pos) => return Err((pos, format!("expected a number, found {t}"))),
+            }
+        }
+
+        if *self.peek() != Tok::Eof {
+            return self.unexpected("`FROM`, `WHERE`, `SORT`, `LIMIT`, or the end of the query");
+        }
+
+        let mut sql = select.to_string();
+        if !conditions.is_empty() {
+            sql += &format!(" WHERE {}", conditions.join(" AND "));
+        }
+        sql += &format!(" ORDER BY {}{limit}", order.join(", "));
+
+        Ok(Compiled {
+            sql,
+            params: std::mem::take(&mut self.params),
+        })
+    }
+
+    // FROM: `#category`, `"folder"` or `"file.norg"`, combined with AND, OR, NOT (or `-`), and
+    // parens
+
+    fn source_or(&mut self) -> PResult<String> {
+        let mut sql = self.source_and()?;
+        while self.eat_keyword("or") {
+            sql = format!("({sql} OR {})", self.source_and()?);
+        }
+        Ok(sql)
+    }
+
+    fn source_and(&mut self) -> PResult<String> {
+        let mut sql = self.source_not()?;
+        while self.eat_keyword("and") {
+            sql = format!("({sql} AND {})", self.source_not()?);
+        }
+        Ok(sql)
+    }
+
+    fn source_not(&mut self) -> PResult<String> {
+        if *self.peek() == Tok::Minus {
+            self.next();
+            return Ok(format!("NOT {}", self.source_not()?));
+        }
+        if self.eat_keyword("not") {
+            return Ok(format!("NOT {}", self.source_not()?));
+        }
+        match self.next() {
+            (Tok::Tag(name), _) => {
+                let p = self.param(name);
+                Ok(format!(
+                    "EXISTS (SELECT 1 FROM categories c WHERE c.file_id = d.id \
+                     AND c.name = {p} COLLATE NOCASE)"
+                ))
+            }
+            (Tok::Str(path), pos) => self.path(&path, pos),
+            (Tok::LParen, _) => {
+                let sql = self.source_or()?;
+                self.close_paren()?;
+                Ok(sql)
+            }
+            (t, pos) => Err((pos, format!("expected a #category or a \"folder\", found {t}"))),
+        }
+    }
+
+    fn path(&mut self, path: &str, pos: usize) -> PResult<String> {
+        let path = path.trim_end_matches('/');
+        let full = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            let Some(root) = self.ctx.workspace else {
+                return Err((pos, "relative paths need a workspace".to_string()));
+            };
+            let rel = path.trim_start_matches("$/").trim_start_matches('$');
+            let root = root.to_string_lossy();
+            let root = root.trim_end_matches('/');
+            if rel.is_empty() {
+                root.to_string()
+            } else {
+                format!("{root}/{rel}")
+            }
+        };
+
+        if full.ends_with(".norg") {
+            let p = self.param(full);
+            Ok(format!("d.path = {p}"))
+        } else {
+            let p = self.param(format!("{}/%", escape_like(&full)));
+            Ok(format!("d.path LIKE {p} ESCAPE '\\'"))
+        }
+    }
+
+    fn close_paren(&mut self) -> PResult<()> {
+        match self.next() {
+            (Tok::RParen, _) => Ok(()),
+            (t, pos) => Err((pos, format!("expected `)`, found {t}"))),
+        }
+    }
+
+    // WHERE: comparisons combined with AND, OR, NOT, and parens
+
+    fn expr_or(&mut self, fields: &'static [Field]) -> PResult<String> {
+        let mut sql = self.expr_and(fields)?;
+        while self.eat_keyword("or") {
+            sql = format!("({sql} OR {})", self.expr_and(fields)?);
+        }
+        Ok(sql)
+    }
+
+    fn expr_and(&mut self, fields: &'static [Field]) -> PResult<String> {
+        let mut sql = self.expr_not(fields)?;
+        while self.eat_keyword("and") {
+            sql = format!("({sql} AND {})", self.expr_not(fields)?);
+        }
+        Ok(sql)
+    }
+
+    fn expr_not(&mut self, fields: &'static [Field]) -> PResult<String> {
+        if self.eat_keyword("not") {
+            return Ok(format!("NOT {}", self.expr_not(fields)?));
+        }
+        if *self.peek() == Tok::LParen {
+            self.next();
+            let sql = self.expr_or(fields)?;
+            self.close_paren()?;
+            return Ok(sql);
+        }
+        self.comparison(fields)
+    }
+
+    fn comparison(&mut self, fields: &'static [Field]) -> PResult<String> {
+        let field = self.field(fields)?;
+        let op = match self.peek() {
+            Tok::Op(op) => *op,
+            // just a field, it has to be set
+            _ => return Ok(format!("{} IS NOT NULL", field.column)),
+        };
+        self.next();
+        let pos = self.pos();
+        let value = self.operand(fields)?;
+        self.compare(field, op, value, pos)
+    }
+
+    fn field(&mut self, fields: &'static [Field]) -> PResult<&'static Field> {
+        match self.next() {
+            (Tok::Word(w), pos) => lookup(fields, &w).ok_or_else(|| {
+                let names = fields.iter().map(|f| f.name).collect::<Vec<_>>().join(", ");
+                (pos, format!("unknown field `{w}`, expected one of {names}"))
+            }),
+            (t, pos) => Err((pos, format!("expected a field, found {t}"))),
+        }
+    }
+
+    fn operand(&mut self, fields: &'static [Field]) -> PResult<Operand> {
+        let today = self.ctx.now.date();
+        Ok(match self.next() {
+            (Tok::Str(s), _) => Operand::Text(s),
+            (Tok::Num(n), _) => Operand::Int(n),
+            (Tok::Minus, pos) => match self.next() {
+                (Tok::Num(n), _) => Operand::Int(-n),
+                _ => return Err((pos, "expected a number after `-`".to_string())),
+            },
+            (Tok::Date(d), _) => Operand::Date(d),
+            (Tok::Word(w), pos) => match w.to_lowercase().as_str() {
+                "null" => Operand::Null,
+                "now" => Operand::Now,
+                "today" => Operand::Date(today),
+                "tomorrow" => Operand::Date(
+                    today
+                        .checked_add_days(Days::new(1))
+                        .ok_or_else(|| (pos, "tomorrow is out of range".to_string()))?,
+                ),
+                "yesterday" => Operand::Date(
+                    today
+                        .checked_sub_days(Days::new(1))
+                        .ok_or_else(|| (pos, "yesterday is out of range".to_string()))?,
+                ),
+                lower => match lookup(fields, &w) {
+                    Some(f) => Operand::Field(f),
+                    None if KEYWORDS.contains(&lower) => {
+                        return Err((pos, format!("expected a value, found `{w}`")))
+                    }
+                    // bare words are text, so `status = done` works
+                    None => Operand::Text(w),
+                },
+            },
+            (t, pos) => return Err((pos, format!("expected a value, found {t}"))),
+        })
+    }
+
+    fn compare(&mut self, field: &Field, op: &str, value: Operand, pos: usize) -> PResult<String> {
+        let col = field.column;
+        // `!=` includes rows without a value, `priority != A` should include unprioritized tasks
+        let sql_op = if op == "!=" { "IS NOT" } else { op };
+        let type_error = |what: &str| Err((pos, format!("`{}` is {what}", field.name)));
+
+        match (field.kind, value) {
+            (_, Operand::Null) => match op {
+                "=" => Ok(format!("{col} IS NULL")),
+                "!=" => Ok(format!("{col} IS NOT NULL")),
+                _ => Err((pos, format!("`null` only works with `=` and `!=`, not `{op}`"))),
+            },
+            (_, Operand::Field(other)) => {
+                if field.kind != other.kind || op == "~" {
+                    return Err((
+                        pos,
+                        format!("`{}` and `{}` can't be compared", field.name, other.name),
+                    ));
+                }
+                Ok(format!("{col} {sql_op} {}", other.column))
+            }
+            (Kind::Text, Operand::Text(s)) => {
+                if op == "~" {
+                    let p = self.param(format!("%{}%", escape_like(&s)));
+                    Ok(format!("{col} LIKE {p} ESCAPE '\\'"))
+                } else {
+                    let p = self.param(s);
+                    Ok(format!("{col} {sql_op} {p} COLLATE NOCASE"))
+                }
+            }
+            (Kind::Text, _) => type_error("text, compare it to a word or a \"string\""),
+            (_, _) if op == "~" => Err((pos, "`~` only works on text".to_string())),
+            (Kind::Int, Operand::Int(n)) => {
+                let p = self.param(n);
+                Ok(format!("{col} {sql_op} {p}"))
+            }
+            (Kind::Int, _) => type_error("a number"),
+            (Kind::Date, Operand::Date(d)) => {
+                // a date is the whole day, `due = today` is anything due today. `<` and `>=` don't
+                // need the next day, so they still work on the last one
+                let next = || {
+                    d.checked_add_days(Days::new(1))
+                        .ok_or_else(|| (pos, format!("{d} is out of range")))
+                };
+                Ok(match op {
+                    "=" | "!=" => {
+                        let start = self.date_param(d, pos)?;
+                        let end = self.date_param(next()?, pos)?;
+                        if op == "=" {
+                            format!("({col} >= {start} AND {col} < {end})")
+                        } else {
+                            format!("({col} IS NULL OR {col} < {start} OR {col} >= {end})")
+                        }
+                    }
+                    "<" => format!("{col} < {}", self.date_param(d, pos)?),
+                    ">=" => format!("{col} >= {}", self.date_param(d, pos)?),
+                    "<=" => format!("{col} < {}", self.date_param(next()?, pos)?),
+                    _ => format!("{col} >= {}", self.date_param(next()?, pos)?),
+                })
+            }
+            (Kind::Date, Operand::Now) => {
+                let now = self
+                    .ctx
+                    .zone
+                    .to_utc(self.ctx.now)
+                    .ok_or_else(|| (pos, "now doesn't exist in this time zone".to_string()))?;
+                let p = self.param(norg_date::to_sql(now));
+                Ok(format!("{col} {sql_op} {p}"))
+            }
+            (Kind::Date, _) => {
+                type_error("a date, compare it to a YYYY-MM-DD date, `today`, or `now`")
+            }
+        }
+    }
+
+    /// The start of `date` in the query's zone
+    fn date_param(&mut self, date: NaiveDate, pos: usize) -> PResult<String> {
+        let utc = self
+            .ctx
+            .zone
+            .to_utc(date.and_time(NaiveTime::MIN))
+            .ok_or_else(|| (pos, format!("{date} doesn't exist in this time zone")))?;
+        Ok(self.param(norg_date::to_sql(utc)))
+    }
+}
+
+fn lookup(fields: &'static [Field], name: &str) -> Option<&'static Field> {
+    fields.iter().find(|f| f.name.eq_ignore_ascii_case(name))
+}
+
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+#[cfg(test)]
+fn test_compile(source: &str) -> Result<Compiled, SyntaxError> {
+    let zone = Zone::Fixed(chrono::FixedOffset::east_opt(0).unwrap());
+    let ctx = QueryContext {
+        workspace: Some(Path::new("/notes")),
+        zone: &zone,
+        now: NaiveDate::from_ymd_opt(2025, 1, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap(),
+    };
+    compile(source, &ctx)
+}
+
+#[test]
+fn compile_tasks_query() {
+    let q = test_compile("TASKS FROM #work WHERE due < today AND status != done SORT due DESC")
+        .unwrap();
+    assert_eq!(
+        q.sql,
+        "SELECT t.*, d.path, d.title FROM tasks t JOIN docs d ON d.id = t.file_id \
+         WHERE EXISTS (SELECT 1 FROM categories c WHERE c.file_id = d.id \
+         AND c.name = ?1 COLLATE NOCASE) \
+         AND (t.due < ?2 AND t.status IS NOT ?3 COLLATE NOCASE) \
+         ORDER BY t.due IS NULL, t.due DESC, d.path, t.start_line"
+    );
+    assert_eq!(q.params.len(), 3);
+    assert!(matches!(&q.params[0], Value::Text(s) if s == "work"));
+    // 2025-01-01 00:00 UTC
+    assert!(matches!(&q.params[1], Value::Text(s) if s == "2025-01-01 00:00:00"));
+    assert!(matches!(&q.params[2], Value::Text(s) if s == "done"));
+}
+
+#[test]
+fn compile_docs_query() {
+    let q = test_compile(
+        r#"docs from ("journal" or "$/daily/") and -#private where title ~ "50%" limit 5"#,
+    )
+    .unwrap();
+    assert_eq!(
+        q.sql,
+        "SELECT d.* FROM docs d \
+         WHERE ((d.path LIKE ?1 ESCAPE '\\' OR d.path LIKE ?2 ESCAPE '\\') \
+         AND NOT EXISTS (SELECT 1 FROM categories c WHERE c.file_id = d.id \
+         AND c.name = ?3 COLLATE NOCASE)) \
+         AND d.title LIKE ?4 ESCAPE '\\' \
+         ORDER BY d.path LIMIT ?5"
+    );
+    assert!(matches!(&q.params[0], Value::Text(s) if s == "/notes/journal/%"));
+    assert!(matches!(&q.params[1], Value::Text(s) if s == "/notes/daily/%"));
+    assert!(matches!(&q.params[3], Value::Text(s) if s == "%50\\%%"));
+
+    let q = test_compile("DOCS WHERE created = 2024-12-31 OR description = null").unwrap();
+    assert!(q
+        .sql
+        .contains("((d.created >= ?1 AND d.created < ?2) OR d.description IS NULL)"));
+    assert!(matches!(&q.params[1], Value::Text(s) if s == "2025-01-01 00:00:00"));
+}
+
+#[test]
+fn query_syntax_errors() {
+    let err = |s: &str| {
+        let e = test_compile(s).unwrap_err();
+        (e.line, e.col)
+    };
+    assert_eq!(err("TASK WHERE due < today"), (1, 1));
+    assert_eq!(err("TASKS WHERE dua < today"), (1, 13));
+    assert_eq!(err("TASKS\nWHERE due < \"soon\""), (2, 13));
+    assert_eq!(err("TASKS WHERE (due < today"), (1, 25));
+    assert_eq!(err("DOCS FROM \"journal"), (1, 11));
+    assert_eq!(err("DOCS LIMIT 10 SORT path"), (1, 15));
+    assert_eq!(err("TASKS WHERE level ~ 3"), (1, 21));
+
+    let e = test_compile("TASKS WHERE dua < today").unwrap_err();
+    assert!(e.to_string().starts_with("1:13: unknown field `dua`"));
+}
+
+#[test]
+fn query_dates_out_of_range() {
+    let zone = Zone::Fixed(chrono::FixedOffset::east_opt(0).unwrap());
+    let compile_at = |source: &str, today: NaiveDate| {
+        let ctx = QueryContext {
+            workspace: None,
+            zone: &zone,
+            now: today.and_time(NaiveTime::MIN),
+        };
+        compile(source, &ctx).map(|_| ()).map_err(|e| e.to_string())
+    };
+
+    // the whole of the last day ends after the last date there is
+    let e = compile_at("TASKS WHERE due = today", NaiveDate::MAX).unwrap_err();
+    assert!(e.starts_with("1:19: "), "{e}");
+    let e = compile_at("TASKS WHERE due < tomorrow", NaiveDate::MAX).unwrap_err();
+    assert!(e.starts_with("1:19: tomorrow is out of range"), "{e}");
+    let e = compile_at("TASKS WHERE due > yesterday", NaiveDate::MIN).unwrap_err();
+    assert!(e.starts_with("1:19: yesterday is out of range"), "{e}");
+    // `<` doesn't need the next day
+    assert!(compile_at("TASKS WHERE due < today", NaiveDate::MAX).is_ok());
+}
@@ -0,0 +1,134 @@
+// Figuring out which task in the freshly parsed document is which task that we already had in the
+// database. Tasks don't have ids in the file, so all we've got to go off of is what they say, what
+// they're nested under, and where they are. Text alone isn't enough: fixing a typo changes it, and
+// two tasks can say the same thing.
+
+/// What we know about a task when we're trying to match it up with another
+#[derive(Debug, Clone)]
+pub struct TaskKey {
+    pub text: String,
+    /// text of each task that this task is nested under, outermost first
+    pub parents: Vec<String>,
+    /// 1-indexed line the task starts on
+    pub line: Option<i64>,
+}
+
+/// How similar the text has to be for two tasks to be considered the same task
+const TEXT_THRESHOLD: f64 = 0.7;
+
+const TEXT_WEIGHT: f64 = 0.6;
+const PARENT_WEIGHT: f64 = 0.25;
+const LINE_WEIGHT: f64 = 0.15;
+
+/// For each of the `new` tasks, the index of the `old` task that it is, if any. Each old task is
+/// matched at most once, best matches are taken first
+pub fn match_tasks(old: &[TaskKey], new: &[TaskKey]) -> Vec<Option<usize>> {
+    let mut pairs = vec![];
+    for (n, new_task) in new.iter().enumerate() {
+        for (o, old_task) in old.iter().enumerate() {
+            if let Some(score) = score(old_task, new_task) {
+                pairs.push((score, n, o));
+            }
+        }
+    }
+    // stable sort, so ties go to the earlier task
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut matches = vec![None; new.len()];
+    let mut taken = vec![false; old.len()];
+    for (_, n, o) in pairs {
+        if matches[n].is_none() && !taken[o] {
+            matches[n] = Some(o);
+            taken[o] = true;
+        }
+    }
+    matches
+}
+
+/// None if the tasks are too different to be the same task, otherwise higher is more alike
+fn score(old: &TaskKey, new: &TaskKey) -> Option<f64> {
+    let text = similarity(&old.text, &new.text)?;
+    // no partial credit here, otherwise two tasks under `Project A` and `Project B` look alike
+    let parent = if old.parents == new.parents { 1.0 } else { 0.0 };
+    let line = match (old.line, new.line) {
+        (Some(a), Some(b)) => 1.0 / (1.0 + (a - b).abs() as f64 / 10.0),
+        _ => 0.0,
+    };
+    Some(TEXT_WEIGHT * text + PARENT_WEIGHT * parent + LINE_WEIGHT * line)
+}
+
+/// 1.0 for identical strings, down to 0.0 for completely different ones. None when the strings
+/// are less similar than [TEXT_THRESHOLD]
+fn similarity(a: &str, b: &str) -> Option<f64> {
+    if a == b {
+        return Some(1.0);
+    }
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len()) as f64;
+    // the distance is at least the difference in length, don't bother when that's already too far
+    if 1.0 - a.len().abs_diff(b.len()) as f64 / longest < TEXT_THRESHOLD {
+        return None;
+    }
+
+    let ratio = 1.0 - levenshtein(&a, &b) as f64 / longest;
+    (ratio >= TEXT_THRESHOLD).then_some(ratio)
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + usize::from(ca != cb);
+            cur[j + 1] = sub.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+fn key(text: &str, parents: &[&str], line: i64) -> TaskKey {
+    TaskKey {
+        text: text.to_string(),
+        parents: parents.iter().map(|p| p.to_string()).collect(),
+        line: Some(line),
+    }
+}
+
+#[test]
+fn match_edited_tasks() {
+    let old = [
+        key("Buy milk", &[], 1),
+        key("Call the bank", &[], 2),
+        key("Write the reprot", &[], 3),
+    ];
+    let new = [
+        key("Buy milk", &[], 1),
+        // typo fixed
+        key("Write the report", &[], 2),
+        key("Something else entirely", &[], 3),
+    ];
+    assert_eq!(match_tasks(&old, &new), vec![Some(0), Some(2), None]);
+}
+
+#[test]
+fn match_duplicate_tasks() {
+    let old = [
+        key("Review", &["Project A"], 2),
+        key("Review", &["Project B"], 6),
+    ];
+    // project B moved above project A
+    let new = [
+        key("Review", &["Project B"], 2),
+        key("Review", &["Project A"], 6),
+    ];
+    assert_eq!(match_tasks(&old, &new), vec![Some(1), Some(0)]);
+
+    // same parent, told apart by position
+    let old = [key("Review", &[], 1), key("Review", &[], 10)];
+    let new = [key("Review", &[], 2), key("Review", &[], 11), key("Review", &[], 20)];
+    assert_eq!(match_tasks(&old, &new), vec![Some(0), Some(1), None]);
+}
@@ -0,0 +1,121 @@
+// Putting flat task rows back together into the trees that they came from. SQL can hand us the
+// tasks that matched and their ancestors (see `task_tree_query`), but nesting them is much easier
+// here than it is in SQL or lua.
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+pub type Row = HashMap<String, Value>;
+
+/// `rows` are the tasks that matched, along with all of their ancestors. `matched` is the ids of
+/// the tasks that matched, in the order the query returned them. Each task gets a `matched` field,
+/// and its `children`. Trees are in the order of their first match, siblings are in the order
+/// they're written in
+pub fn build(rows: Vec<Row>, matched: &[i64]) -> Vec<Value> {
+    let id = |row: &Row| row.get("task_id").and_then(Value::as_i64);
+    let parent = |row: &Row| row.get("parent_id").and_then(Value::as_i64);
+
+    let mut by_id: HashMap<i64, Row> = HashMap::new();
+    for row in rows {
+        if let Some(i) = id(&row) {
+            by_id.insert(i, row);
+        }
+    }
+
+    // a tree is ranked by the earliest match in it
+    let mut rank: HashMap<i64, usize> = HashMap::new();
+    for (r, &m) in matched.iter().enumerate() {
+        let mut cur = Some(m);
+        // bounded, just in case the parents loop
+        for _ in 0..=by_id.len() {
+            let Some(c) = cur else { break };
+            if rank.get(&c).is_some_and(|&old| old <= r) {
+                break;
+            }
+            rank.insert(c, r);
+            cur = by_id.get(&c).and_then(parent);
+        }
+    }
+
+    let mut children: HashMap<i64, Vec<i64>> = HashMap::new();
+    let mut roots = vec![];
+    for (&i, row) in &by_id {
+        match parent(row).filter(|p| by_id.contains_key(p)) {
+            Some(p) => children.entry(p).or_default().push(i),
+            None => roots.push(i),
+        }
+    }
+
+    let line = |i: &i64| {
+        by_id[i]
+            .get("start_line")
+            .and_then(Value::as_i64)
+            .unwrap_or(i64::MAX)
+    };
+    roots.sort_by_key(|i| (rank.get(i).copied().unwrap_or(usize::MAX), line(i), *i));
+    for siblings in children.values_mut() {
+        siblings.sort_by_key(|i| (line(i), *i));
+    }
+
+    let matched: HashSet<i64> = matched.iter().copied().collect();
+    roots
+        .iter()
+        .map(|&i| assemble(i, &mut by_id, &children, &matched))
+        .collect()
+}
+
+fn assemble(
+    i: i64,
+    by_id: &mut HashMap<i64, Row>,
+    children: &HashMap<i64, Vec<i64>>,
+    matched: &HashSet<i64>,
+) -> Value {
+    let mut row = by_id.remove(&i).unwrap_or_default();
+    let kids = children
+        .get(&i)
+        .map(|c| c.iter().map(|&c| assemble(c, by_id, children, matched)).collect())
+        .unwrap_or_default();
+    row.insert("matched".to_string(), Value::Bool(matched.contains(&i)));
+    row.insert("children".to_string(), Value::Array(kids));
+    Value::Object(row.into_iter().collect())
+}
+
+#[cfg(test)]
+fn task(id: i64, parent: Option<i64>, line: i64) -> Row {
+    let mut row = Row::new();
+    row.insert("task_id".to_string(), id.into());
+    if let Some(p) = parent {
+        row.insert("parent_id".to_string(), p.into());
+    }
+    row.insert("start_line".to_string(), line.into());
+    row
+}
+
+#[test]
+fn build_task_tree() {
+    // 1
+    // - 2
+    //   - 3 (matched)
+    // - 4 (matched)
+    // 5 (matched, first)
+    let rows = vec![
+        task(3, Some(2), 3),
+        task(1, None, 1),
+        task(4, Some(1), 4),
+        task(2, Some(1), 2),
+        task(5, None, 10),
+    ];
+    let tree = build(rows, &[5, 4, 3]);
+
+    let ids = |v: &Value| v["task_id"].as_i64().unwrap();
+    assert_eq!(tree.iter().map(ids).collect::<Vec<_>>(), vec![5, 1]);
+    assert_eq!(tree[0]["matched"], Value::Bool(true));
+    assert_eq!(tree[1]["matched"], Value::Bool(false));
+
+    let kids = tree[1]["children"].as_array().unwrap();
+    assert_eq!(kids.iter().map(ids).collect::<Vec<_>>(), vec![2, 4]);
+    assert_eq!(kids[0]["matched"], Value::Bool(false));
+    assert_eq!(ids(&kids[0]["children"][0]), 3);
+    assert_eq!(kids[0]["children"][0]["matched"], Value::Bool(true));
+}
@@ -0,0 +1,135 @@
+// Keeping the index up to date with changes that don't come from neovim: other editors, git pulls,
+// sync tools. Events are debounced per path, so a burst of writes to the same file (or a checkout
+// that touches hundreds of files) is handled once the dust settles.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{error, info};
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use tokio::runtime::Handle;
+
+use crate::orchestrator::{self, IndexOptions};
+use crate::DatabaseConnection;
+
+/// How long a path has to be quiet before we look at it
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watch `root` recursively, changes are indexed on `handle`. Watching stops when the returned
+/// debouncer is dropped
+pub fn watch(
+    root: &Path,
+    conn: DatabaseConnection,
+    opts: IndexOptions,
+    handle: Handle,
+) -> Result<Debouncer<RecommendedWatcher>> {
+    let mut debouncer = new_debouncer(DEBOUNCE, move |res: DebounceEventResult| match res {
+        Ok(events) => {
+            let paths: HashSet<PathBuf> = events.into_iter().map(|e| e.path).collect();
+            let conn = conn.clone();
+            let opts = opts.clone();
+            handle.spawn(async move {
+                for path in paths {
+                    if let Err(e) = update(&path, &conn, &opts).await {
+                        error!("Failed to update {path:?} after it changed: {e:?}");
+                    }
+                }
+            });
+        }
+        Err(e) => error!("Watcher error: {e:?}"),
+    })?;
+    debouncer.watcher().watch(root, RecursiveMode::Recursive)?;
+    info!("Watching {root:?}");
+
+    Ok(debouncer)
+}
+
+/// Bring the index in line with whatever is at `path` now. We don't get told what happened, just
+/// that something did, so this looks at the path instead of the event
+async fn update(path: &Path, conn: &DatabaseConnection, opts: &IndexOptions) -> Result<()> {
+    // the same files that the walk skips, `.git` is busy and never has anything for us
+    if orchestrator::ignored(path, opts.workspace.as_deref()) {
+        return Ok(());
+    }
+    if path.is_file() {
+        if orchestrator::indexable(path, opts.workspace.as_deref()) {
+            info!("{path:?} changed");
+            orchestrator::index_file(path, conn, opts).await?;
+        }
+    } else if path.is_dir() {
+        // a directory that was moved (or copied) in. Unchanged files are skipped, so this is cheap
+        // when it's a directory we already know about
+        orchestrator::index_tree(path, conn, opts).await?;
+    } else {
+        // deleted, or renamed away. Works for a single file or a whole directory
+        let removed = orchestrator::remove_under(path, conn).await?;
+        info!("{path:?} is gone, removed {removed} doc(s)");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn update_follows_the_disk() {
+    use std::fs;
+
+    let dir = crate::db::util::test_dir("watcher");
+    let conn = DatabaseConnection::new(&dir.join("test.sqlite")).await.unwrap();
+    let ws = dir.join("ws");
+    fs::create_dir_all(&ws).unwrap();
+    let opts = IndexOptions {
+        workspace: Some(ws.clone()),
+        ..IndexOptions::default()
+    };
+    let titles = || {
+        let conn = conn.clone();
+        async move {
+            let mut rows = conn
+                .user_query("SELECT path, title FROM docs ORDER BY path", ())
+                .await
+                .unwrap();
+            let mut titles: Vec<(String, String)> = vec![];
+            while let Some(row) = rows.next().await.unwrap() {
+                titles.push((row.get(0).unwrap(), row.get(1).unwrap()));
+            }
+            titles
+        }
+    };
+    let write = |path: &Path, title: &str| {
+        fs::write(path, format!("@document.meta\ntitle: {title}\n@end\n")).unwrap();
+    };
+    let a = ws.join("a.norg");
+    let a_path = a.to_string_lossy().to_string();
+
+    // created
+    write(&a, "one");
+    update(&a, &conn, &opts).await.unwrap();
+    assert_eq!(titles().await, [(a_path.clone(), "one".to_string())]);
+
+    // modified
+    write(&a, "two");
+    update(&a, &conn, &opts).await.unwrap();
+    assert_eq!(titles().await, [(a_path.clone(), "two".to_string())]);
+
+    // hidden (and ignored) files and directories are left alone, like the walk does
+    fs::create_dir_all(ws.join(".git")).unwrap();
+    write(&ws.join(".git/b.norg"), "hidden");
+    update(&ws.join(".git"), &conn, &opts).await.unwrap();
+    update(&ws.join(".git/b.norg"), &conn, &opts).await.unwrap();
+    assert_eq!(titles().await.len(), 1);
+
+    // a directory moved in
+    fs::create_dir_all(ws.join("sub")).unwrap();
+    write(&ws.join("sub/c.norg"), "three");
+    update(&ws.join("sub"), &conn, &opts).await.unwrap();
+    assert_eq!(titles().await.len(), 2);
+
+    // deleted, a file and then a whole directory
+    fs::remove_file(&a).unwrap();
+    update(&a, &conn, &opts).await.unwrap();
+    fs::remove_dir_all(ws.join("sub")).unwrap();
+    update(&ws.join("sub"), &conn, &opts).await.unwrap();
+    assert!(titles().await.is_empty());
+}